@@ -0,0 +1,362 @@
+//! Looking through a directory for entries that cause trouble in a dotfiles repository.
+//!
+//! None of these stop a save. They are reported before the plan runs, each with an `--exclude`
+//! glob that leaves it behind:
+//!
+//! - Nested git repositories, which git records as an embedded repository instead of their files.
+//! - SQLite databases with `-wal`, `-shm` or `-journal` companions, which are open and changing.
+//! - Lock files and PID files, which only mean something to the process that wrote them.
+//! - Sockets, which can't be committed.
+//! - Editor swap files, like `.init.lua.swp` or `#init.el#`.
+use std::fmt;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use serde::Serialize;
+
+use crate::filesystem::{FileType, Filesystem};
+use crate::selection::{Selection, Visit};
+
+/// Lock files of package managers, which belong in a repository.
+const PACKAGE_LOCK_FILES: &[&str] = &[
+    "Cargo.lock",
+    "Gemfile.lock",
+    "Pipfile.lock",
+    "composer.lock",
+    "flake.lock",
+    "mix.lock",
+    "poetry.lock",
+    "pubspec.lock",
+    "yarn.lock",
+];
+
+/// What kind of trouble an entry causes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Category {
+    NestedRepository,
+    SqliteDatabase,
+    LockFile,
+    PidFile,
+    Socket,
+    SwapFile,
+}
+
+impl Category {
+    const ALL: [Category; 6] = [
+        Category::NestedRepository,
+        Category::SqliteDatabase,
+        Category::LockFile,
+        Category::PidFile,
+        Category::Socket,
+        Category::SwapFile,
+    ];
+
+    fn title(self) -> &'static str {
+        match self {
+            Category::NestedRepository => {
+                "Nested git repositories, which git would record as embedded repositories"
+            }
+            Category::SqliteDatabase => "SQLite databases that are in use",
+            Category::LockFile => "Lock files",
+            Category::PidFile => "PID files",
+            Category::Socket => "Sockets",
+            Category::SwapFile => "Editor swap files",
+        }
+    }
+}
+
+/// An entry that causes trouble.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct Problem {
+    pub category: Category,
+    /// The entry, relative to the audited directory. Empty for the directory itself.
+    pub path: PathBuf,
+    /// A glob for `--exclude` that leaves the entry behind, if there is one.
+    pub exclude: Option<String>,
+}
+
+/// Look for trouble in the entries of the directory `root` that `selection` takes, ordered by
+/// category. Special files are looked at whatever the selection's policy for them, since
+/// sockets are trouble either way.
+pub fn audit(fs: &dyn Filesystem, root: &Path, selection: &Selection) -> io::Result<Vec<Problem>> {
+    let mut problems = Vec::new();
+    audit_entry(
+        fs,
+        root,
+        Path::new(""),
+        Visit::Descend,
+        selection,
+        &mut problems,
+    )?;
+    problems.sort_by(|a, b| (a.category, &a.path).cmp(&(b.category, &b.path)));
+    problems.dedup();
+    Ok(problems)
+}
+
+/// A report of `problems` by category, ending with the `--exclude` options that leave them
+/// behind.
+pub fn describe(problems: &[Problem]) -> String {
+    let mut report =
+        String::from("Some entries are likely to cause trouble in a dotfiles repository:");
+    for category in Category::ALL {
+        let mut in_category = problems
+            .iter()
+            .filter(|problem| problem.category == category);
+        let Some(first) = in_category.next() else {
+            continue;
+        };
+        report.push_str(&format!("\n{}:", category.title()));
+        for problem in std::iter::once(first).chain(in_category) {
+            report.push_str(&format!("\n  {}", ShowPath(&problem.path)));
+        }
+    }
+    let mut excludes: Vec<&str> = Vec::new();
+    for exclude in problems
+        .iter()
+        .filter_map(|problem| problem.exclude.as_deref())
+    {
+        if !excludes.contains(&exclude) {
+            excludes.push(exclude);
+        }
+    }
+    if !excludes.is_empty() {
+        let options: Vec<String> = excludes
+            .iter()
+            .map(|exclude| format!("--exclude '{}'", exclude))
+            .collect();
+        report.push_str(&format!(
+            "\nTo leave them behind, save with {}",
+            options.join(" ")
+        ));
+    }
+    report
+}
+
+/// Shows the audited directory itself as `.`.
+struct ShowPath<'a>(&'a Path);
+
+impl fmt::Display for ShowPath<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.0.as_os_str().is_empty() {
+            f.write_str(".")
+        } else {
+            write!(f, "{}", self.0.display())
+        }
+    }
+}
+
+fn audit_entry(
+    fs: &dyn Filesystem,
+    path: &Path,
+    relative: &Path,
+    parent: Visit,
+    selection: &Selection,
+    problems: &mut Vec<Problem>,
+) -> io::Result<()> {
+    let file_type = fs.symlink_metadata(path)?.file_type;
+    let visit = selection.visit_patterns(relative, file_type, parent);
+    if visit == Visit::Skip {
+        return Ok(());
+    }
+    let name = relative
+        .file_name()
+        .map(|name| name.to_string_lossy())
+        .unwrap_or_default();
+    if name == ".git" {
+        // A `.git` file is a submodule or a worktree. Either way, don't look inside.
+        let repository = relative.parent().unwrap();
+        problems.push(Problem {
+            category: Category::NestedRepository,
+            path: repository.to_owned(),
+            // Leaving only `.git` behind would break the repository, so leave all of it.
+            exclude: (!repository.as_os_str().is_empty()).then(|| glob(repository)),
+        });
+        return Ok(());
+    }
+    if file_type.is_dir() {
+        for entry in fs.read_dir(path)? {
+            let child = relative.join(entry.file_name().unwrap());
+            audit_entry(fs, &entry, &child, visit, selection, problems)?;
+        }
+        return Ok(());
+    }
+    if visit != Visit::Take {
+        return Ok(());
+    }
+    let problem = |category, exclude: String| Problem {
+        category,
+        path: relative.to_owned(),
+        exclude: Some(exclude),
+    };
+    if file_type == FileType::Socket {
+        problems.push(problem(Category::Socket, glob(relative)));
+    } else if let Some(database) = ["-wal", "-shm", "-journal"]
+        .iter()
+        .find_map(|suffix| name.strip_suffix(suffix))
+        .filter(|database| fs.is_file(&path.with_file_name(database)))
+    {
+        let database = relative.with_file_name(database);
+        // The database and its companions.
+        let exclude = format!("{}*", glob(&database));
+        problems.push(Problem {
+            category: Category::SqliteDatabase,
+            path: database,
+            exclude: Some(exclude),
+        });
+    } else if is_lock_file(&name) {
+        problems.push(problem(Category::LockFile, glob(relative)));
+    } else if name.ends_with(".pid") {
+        problems.push(problem(Category::PidFile, glob(relative)));
+    } else if let Some(pattern) = swap_file_pattern(&name) {
+        problems.push(problem(Category::SwapFile, pattern.to_string()));
+    }
+    Ok(())
+}
+
+fn is_lock_file(name: &str) -> bool {
+    let is_lock = matches!(name, "lock" | "LOCK" | "lockfile" | ".lock")
+        || name.ends_with(".lock")
+        || name.ends_with(".lck")
+        || name.starts_with(".~lock.");
+    is_lock && !PACKAGE_LOCK_FILES.contains(&name)
+}
+
+/// The glob that matches swap files like `name`, if it is one.
+fn swap_file_pattern(name: &str) -> Option<&'static str> {
+    if name.len() > 2 && name.starts_with('#') && name.ends_with('#') {
+        return Some("#*#");
+    }
+    if name.starts_with(".#") {
+        return Some(".#*");
+    }
+    [".swp", ".swo", ".swn"]
+        .into_iter()
+        .find(|extension| name.starts_with('.') && name.ends_with(extension))
+        .map(|extension| match extension {
+            ".swp" => ".*.swp",
+            ".swo" => ".*.swo",
+            _ => ".*.swn",
+        })
+}
+
+/// `path` as a glob that matches only itself.
+fn glob(path: &Path) -> String {
+    globset::escape(&path.to_string_lossy())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::copy::SpecialFiles;
+    use crate::filesystem::{MemoryFs, RealFs};
+
+    /// A tree shaped like `~/.config/nvim` after some use.
+    fn create_tree(root: &Path) -> MemoryFs {
+        let fs = MemoryFs::new();
+        let plugin = root.join("pack/plugins/start/fugitive");
+        fs.create_dir_all(&plugin.join(".git")).unwrap();
+        fs.write(&plugin.join(".git/index.lock"), "").unwrap();
+        fs.write(&plugin.join("plugin.vim"), "").unwrap();
+        fs.create_dir_all(&root.join("lua")).unwrap();
+        fs.write(&root.join("init.lua"), "").unwrap();
+        fs.write(&root.join(".init.lua.swp"), "").unwrap();
+        fs.write(&root.join("lua/#plugins.lua#"), "").unwrap();
+        fs.write(&root.join("lazy-lock.json"), "{}").unwrap();
+        fs.write(&root.join("flake.lock"), "{}").unwrap();
+        fs.write(&root.join("server.pid"), "42").unwrap();
+        fs.write(&root.join("session.lock"), "").unwrap();
+        fs.write(&root.join("history.db"), "").unwrap();
+        fs.write(&root.join("history.db-wal"), "").unwrap();
+        fs.write(&root.join("history.db-shm"), "").unwrap();
+        fs.write(&root.join("notes-shm"), "").unwrap();
+        fs
+    }
+
+    #[test]
+    fn test_audit() {
+        let root = Path::new("/home/user/.config/nvim");
+        let fs = create_tree(root);
+
+        let problems = audit(&fs, root, &Selection::default()).unwrap();
+        let found: Vec<(Category, &str, Option<&str>)> = problems
+            .iter()
+            .map(|problem| {
+                (
+                    problem.category,
+                    problem.path.to_str().unwrap(),
+                    problem.exclude.as_deref(),
+                )
+            })
+            .collect();
+        assert_eq!(
+            found,
+            vec![
+                (
+                    Category::NestedRepository,
+                    "pack/plugins/start/fugitive",
+                    Some("pack/plugins/start/fugitive")
+                ),
+                (Category::SqliteDatabase, "history.db", Some("history.db*")),
+                (Category::LockFile, "session.lock", Some("session.lock")),
+                (Category::PidFile, "server.pid", Some("server.pid")),
+                (Category::SwapFile, ".init.lua.swp", Some(".*.swp")),
+                (Category::SwapFile, "lua/#plugins.lua#", Some("#*#")),
+            ]
+        );
+
+        let report = describe(&problems);
+        assert!(report.contains("\nPID files:\n  server.pid\n"));
+        assert!(report.ends_with(
+            "--exclude 'pack/plugins/start/fugitive' --exclude 'history.db*' \
+             --exclude 'session.lock' --exclude 'server.pid' --exclude '.*.swp' --exclude '#*#'"
+        ));
+    }
+
+    #[test]
+    fn test_audit_respects_selection() {
+        let root = Path::new("/home/user/.config/nvim");
+        let fs = create_tree(root);
+        let exclude: Vec<String> = vec!["pack".into(), "history.db*".into(), "*.swp".into()];
+        let selection = Selection::new(SpecialFiles::Abort, &exclude, &[]).unwrap();
+
+        let problems = audit(&fs, root, &selection).unwrap();
+        assert!(problems.iter().all(|problem| !matches!(
+            problem.category,
+            Category::NestedRepository | Category::SqliteDatabase
+        )));
+        assert!(!problems
+            .iter()
+            .any(|problem| problem.path == Path::new(".init.lua.swp")));
+    }
+
+    #[test]
+    fn test_audit_finds_sockets() {
+        let dir = tempfile::tempdir().unwrap();
+        let _listener =
+            std::os::unix::net::UnixListener::bind(dir.path().join("agent.sock")).unwrap();
+        let selection = Selection::new(SpecialFiles::Exclude, &[], &[]).unwrap();
+
+        let problems = audit(&RealFs, dir.path(), &selection).unwrap();
+        assert_eq!(
+            problems,
+            vec![Problem {
+                category: Category::Socket,
+                path: PathBuf::from("agent.sock"),
+                exclude: Some("agent.sock".into()),
+            }]
+        );
+    }
+
+    #[test]
+    fn test_audit_repository_itself() {
+        let root = Path::new("/home/user/.config/nvim");
+        let fs = MemoryFs::new();
+        fs.create_dir_all(&root.join(".git")).unwrap();
+
+        let problems = audit(&fs, root, &Selection::default()).unwrap();
+        assert_eq!(problems.len(), 1);
+        assert_eq!(problems[0].exclude, None);
+        assert!(describe(&problems).ends_with(":\n  ."));
+    }
+}
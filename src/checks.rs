@@ -1,10 +1,21 @@
-use std::path::Path;
+use std::fmt;
+use std::io;
+use std::path::{Path, PathBuf};
 
 use anyhow::Result;
 use thiserror::Error;
 
+use crate::config::Limits;
+use crate::copy::{SpecialFile, SpecialFiles};
+use crate::filesystem::Filesystem;
+use crate::secrets::Finding;
+use crate::selection::Partition;
+use crate::stow::IgnoreRules;
+use crate::usage::{self, Usage};
+
 #[derive(Error, Debug)]
-enum StowSaveError {
+#[non_exhaustive]
+pub enum StowSaveError {
     #[error("Path '{0}' does not exist")]
     PathDoesNotExist(String),
     #[error("Path '{0}' is a symlink. Cannot save symlinks.")]
@@ -13,122 +24,659 @@ enum StowSaveError {
     InvalidStowDirectory(String),
     #[error("Path '{0}' already exists in the stow directory")]
     PathAlreadyExists(String),
+    #[error("Backup '{0}' already exists. Move it out of the way first")]
+    BackupAlreadyExists(String),
+    #[error("Path '{0}' is already saved in stow package '{1}'")]
+    AlreadyInPackage(String, String),
+    #[error("Refusing to save the root directory")]
+    PathIsRoot,
+    #[error("Refusing to save the home directory '{0}'. Save the files inside it instead")]
+    PathIsHome(String),
+    #[error("Path '{0}' is the stow directory itself")]
+    PathIsStowDirectory(String),
+    #[error("Path '{0}' is inside the stow directory '{1}'")]
+    PathInsideStowDirectory(String, String),
+    #[error("Path '{0}' contains the stow directory '{1}'")]
+    PathContainsStowDirectory(String, String),
+    #[error("Path '{0}' is covered by '{1}' on the deny-list. Use --force to save it anyway")]
+    PathIsDenied(String, String),
+    #[error("Path '{0}' contains special files that can't be saved:\n{1}\nUse --special-files exclude or --special-files recreate to save the rest")]
+    ContainsSpecialFiles(String, String),
+    #[error("Path '{0}' contains files with other hard links, which would keep the old contents:\n{1}\nUse --allow-hardlinks to save it anyway")]
+    ContainsHardlinks(String, String),
+    #[error("Path '{0}' looks like it contains secrets:\n{1}\nList false positives in '.stowsave-allow-secrets' in the package, or use --allow-secrets to save it anyway")]
+    ContainsSecrets(String, String),
+    #[error("Path '{0}' is larger than the limits of {1}:\n{2}\nUse --exclude to leave parts of it behind, raise the limits in the config file, or use --allow-large to save it anyway")]
+    TooLarge(String, String, String),
+    #[error("Nothing inside '{0}' matches the given --include and --exclude globs")]
+    NothingSelected(String),
+    #[error("Path '{0}' is outside of the stow target directory '{1}'")]
+    PathOutsideStowTarget(String, String),
+    #[error("Stow ignores '{0}', so it would not be linked back into place. Check the --ignore options and the ignore lists of stow")]
+    PathIsIgnored(String),
+    #[error("Path '{0}' is not a symlink into a stow package, so there is nothing to unfold. Set $STOW_DIR if the stow directory isn't inside the target directory")]
+    NotAFoldedDirectory(String),
+    #[error("Path '{0}' is not a symlink into a stow package, so it isn't saved. If a directory above it is such a symlink, unfold that first. Set $STOW_DIR if the stow directory isn't inside the target directory")]
+    NotSaved(String),
 }
 
-pub(super) fn path_to_save_exists(path_to_save: &Path) -> Result<()> {
-    if !path_to_save.exists() {
+impl StowSaveError {
+    /// A stable identifier of the kind of error, for scripts that read `--output json`.
+    pub fn code(&self) -> &'static str {
+        match self {
+            StowSaveError::PathDoesNotExist(_) => "path_does_not_exist",
+            StowSaveError::PathIsSymlink(_) => "path_is_symlink",
+            StowSaveError::InvalidStowDirectory(_) => "invalid_stow_directory",
+            StowSaveError::PathAlreadyExists(_) => "path_already_exists",
+            StowSaveError::BackupAlreadyExists(_) => "backup_already_exists",
+            StowSaveError::AlreadyInPackage(..) => "already_in_package",
+            StowSaveError::PathIsRoot => "path_is_root",
+            StowSaveError::PathIsHome(_) => "path_is_home",
+            StowSaveError::PathIsStowDirectory(_) => "path_is_stow_directory",
+            StowSaveError::PathInsideStowDirectory(..) => "path_inside_stow_directory",
+            StowSaveError::PathContainsStowDirectory(..) => "path_contains_stow_directory",
+            StowSaveError::PathIsDenied(..) => "path_is_denied",
+            StowSaveError::ContainsSpecialFiles(..) => "contains_special_files",
+            StowSaveError::ContainsHardlinks(..) => "contains_hardlinks",
+            StowSaveError::ContainsSecrets(..) => "contains_secrets",
+            StowSaveError::TooLarge(..) => "too_large",
+            StowSaveError::NothingSelected(_) => "nothing_selected",
+            StowSaveError::PathOutsideStowTarget(..) => "path_outside_stow_target",
+            StowSaveError::PathIsIgnored(_) => "path_is_ignored",
+            StowSaveError::NotAFoldedDirectory(_) => "not_a_folded_directory",
+            StowSaveError::NotSaved(_) => "not_saved",
+        }
+    }
+}
+
+pub fn path_to_save_exists(fs: &dyn Filesystem, path_to_save: &Path) -> Result<()> {
+    // A dangling symlink exists too, it just can't be followed.
+    if fs.symlink_metadata(path_to_save).is_err() {
         return Err(
             StowSaveError::PathDoesNotExist(path_to_save.to_string_lossy().into_owned()).into(),
         );
     }
     Ok(())
 }
-pub(super) fn path_to_save_is_not_symlink(path_to_save: &Path) -> Result<()> {
-    if path_to_save.is_symlink() {
+pub fn path_to_save_is_not_symlink(fs: &dyn Filesystem, path_to_save: &Path) -> Result<()> {
+    if fs.is_symlink(path_to_save) {
         return Err(
             StowSaveError::PathIsSymlink(path_to_save.to_string_lossy().into_owned()).into(),
         );
     }
     Ok(())
 }
-pub(super) fn stow_directory_exists(stow_dir: &Path) -> Result<()> {
-    if !stow_dir.is_dir() {
+/// A folded directory is a symlink to a directory inside a package of `stow_dir`. Without a
+/// stow directory, nothing counts as folded.
+pub fn path_is_folded_directory(
+    fs: &dyn Filesystem,
+    path: &Path,
+    stow_dir: Option<&Path>,
+) -> Result<()> {
+    let is_folded = fs.is_symlink(path)
+        && fs.is_dir(path)
+        && stow_dir.is_some_and(|stow_dir| {
+            fs.canonicalize(path)
+                .is_ok_and(|target| target.starts_with(stow_dir) && target != stow_dir)
+        });
+    if !is_folded {
+        return Err(StowSaveError::NotAFoldedDirectory(path.to_string_lossy().into_owned()).into());
+    }
+    Ok(())
+}
+/// Check that `path` is a symlink to an entry inside a package of `stow_dir`, as saving leaves
+/// it.
+pub fn path_is_saved(fs: &dyn Filesystem, path: &Path, stow_dir: Option<&Path>) -> Result<()> {
+    let is_saved = fs.is_symlink(path)
+        && stow_dir.is_some_and(|stow_dir| {
+            fs.canonicalize(path).is_ok_and(|target| {
+                target
+                    .strip_prefix(stow_dir)
+                    .is_ok_and(|in_stow_dir| in_stow_dir.iter().count() > 1)
+            })
+        });
+    if !is_saved {
+        return Err(StowSaveError::NotSaved(path.to_string_lossy().into_owned()).into());
+    }
+    Ok(())
+}
+pub fn stow_directory_exists(fs: &dyn Filesystem, stow_dir: &Path) -> Result<()> {
+    if !fs.is_dir(stow_dir) {
         return Err(
             StowSaveError::InvalidStowDirectory(stow_dir.to_string_lossy().into_owned()).into(),
         );
     }
     Ok(())
 }
-pub(super) fn target_path_does_not_exist(target_path: &Path) -> Result<()> {
-    if target_path.exists() {
+pub fn target_path_does_not_exist(fs: &dyn Filesystem, target_path: &Path) -> Result<()> {
+    if fs.exists(target_path) {
         return Err(
             StowSaveError::PathAlreadyExists(target_path.to_string_lossy().into_owned()).into(),
         );
     }
     Ok(())
 }
+/// Refuse to overwrite an earlier backup, including a dangling symlink in its place.
+pub fn backup_path_does_not_exist(fs: &dyn Filesystem, backup_path: &Path) -> Result<()> {
+    if fs.symlink_metadata(backup_path).is_ok() {
+        return Err(
+            StowSaveError::BackupAlreadyExists(backup_path.to_string_lossy().into_owned()).into(),
+        );
+    }
+    Ok(())
+}
+pub fn path_to_save_is_not_root(path_to_save: &Path) -> Result<()> {
+    if path_to_save.parent().is_none() {
+        return Err(StowSaveError::PathIsRoot.into());
+    }
+    Ok(())
+}
 
-/// The stow directory should be precisely two generators below the common ancestor
-pub(super) fn stow_directory_is_grandchild_of_common_ancestor(
+pub fn path_to_save_is_not_home(
+    fs: &dyn Filesystem,
+    path_to_save: &Path,
+    home: &Path,
+) -> Result<()> {
+    if same_path(fs, path_to_save, home) {
+        return Err(StowSaveError::PathIsHome(path_to_save.to_string_lossy().into_owned()).into());
+    }
+    Ok(())
+}
+
+pub fn path_to_save_is_not_stow_directory(
+    fs: &dyn Filesystem,
+    path_to_save: &Path,
+    stow_dir: &Path,
+) -> Result<()> {
+    if same_path(fs, path_to_save, stow_dir) {
+        return Err(StowSaveError::PathIsStowDirectory(
+            path_to_save.to_string_lossy().into_owned(),
+        )
+        .into());
+    }
+    Ok(())
+}
+
+pub fn path_to_save_is_not_inside_stow_directory(
+    path_to_save: &Path,
     stow_dir: &Path,
-    common_ancestor: &Path,
 ) -> Result<()> {
-    if stow_dir.strip_prefix(common_ancestor)?.ancestors().count() != 3 {
-        return Err(anyhow::anyhow!(
-            "The stow directory must be a grandchild of the common ancestor"
-        ));
+    if path_to_save.starts_with(stow_dir) && path_to_save != stow_dir {
+        return Err(StowSaveError::PathInsideStowDirectory(
+            path_to_save.to_string_lossy().into_owned(),
+            stow_dir.to_string_lossy().into_owned(),
+        )
+        .into());
+    }
+    Ok(())
+}
+
+pub fn path_to_save_is_not_ancestor_of_stow_directory(
+    path_to_save: &Path,
+    stow_dir: &Path,
+) -> Result<()> {
+    if stow_dir.starts_with(path_to_save) && path_to_save != stow_dir {
+        return Err(StowSaveError::PathContainsStowDirectory(
+            path_to_save.to_string_lossy().into_owned(),
+            stow_dir.to_string_lossy().into_owned(),
+        )
+        .into());
+    }
+    Ok(())
+}
+
+/// Paths on the deny-list, and anything inside them, are only saved with `--force`.
+pub fn path_to_save_is_not_denied(
+    fs: &dyn Filesystem,
+    path_to_save: &Path,
+    deny_list: &[PathBuf],
+    force: bool,
+) -> Result<()> {
+    if force {
+        return Ok(());
+    }
+    for denied in deny_list {
+        let canonical = fs.canonicalize(denied).unwrap_or_else(|_| denied.clone());
+        if path_to_save.starts_with(denied) || path_to_save.starts_with(&canonical) {
+            return Err(StowSaveError::PathIsDenied(
+                path_to_save.to_string_lossy().into_owned(),
+                denied.to_string_lossy().into_owned(),
+            )
+            .into());
+        }
+    }
+    Ok(())
+}
+
+/// Compare paths as given and after resolving symlinks, since `$HOME` may itself be a symlink.
+fn same_path(fs: &dyn Filesystem, a: &Path, b: &Path) -> bool {
+    a == b || fs.canonicalize(b).is_ok_and(|b| a == b)
+}
+
+/// Find an ancestor of `path` that is a symlink into `stow_dir`, which means stow "folded" that
+/// directory into a single link to a package.
+pub fn find_folded_ancestor(fs: &dyn Filesystem, path: &Path, stow_dir: &Path) -> Option<PathBuf> {
+    path.ancestors()
+        .skip(1)
+        .collect::<Vec<_>>()
+        .into_iter()
+        .rev()
+        .find(|ancestor| {
+            fs.is_symlink(ancestor)
+                && fs
+                    .canonicalize(ancestor)
+                    .is_ok_and(|target| target.starts_with(stow_dir))
+        })
+        .map(Path::to_owned)
+}
+
+/// A path that physically lives in the package is already saved there.
+pub fn path_to_save_is_not_in_package(physical_path: &Path, stow_pkg: &Path) -> Result<()> {
+    if physical_path.starts_with(stow_pkg) {
+        return Err(StowSaveError::AlreadyInPackage(
+            physical_path.to_string_lossy().into_owned(),
+            stow_pkg.to_string_lossy().into_owned(),
+        )
+        .into());
+    }
+    Ok(())
+}
+
+/// Stow links the package into the target directory, so only paths inside it can be saved.
+pub fn path_to_save_is_inside_stow_target(path_to_save: &Path, stow_target: &Path) -> Result<()> {
+    if !path_to_save.starts_with(stow_target) {
+        return Err(StowSaveError::PathOutsideStowTarget(
+            path_to_save.to_string_lossy().into_owned(),
+            stow_target.to_string_lossy().into_owned(),
+        )
+        .into());
     }
     Ok(())
 }
 
+/// A path that stow ignores would be moved into the package and never linked back.
+pub fn path_to_save_is_not_ignored(
+    path_to_save: &Path,
+    relative_path: &Path,
+    ignore_rules: &IgnoreRules,
+) -> Result<()> {
+    if ignore_rules.ignores(relative_path) {
+        return Err(
+            StowSaveError::PathIsIgnored(path_to_save.to_string_lossy().into_owned()).into(),
+        );
+    }
+    Ok(())
+}
+
+/// Special files can only be saved if the user told us what to do with them.
+pub fn special_files_are_handled(
+    path_to_save: &Path,
+    special_files: &[SpecialFile],
+    policy: SpecialFiles,
+) -> Result<()> {
+    if policy == SpecialFiles::Abort && !special_files.is_empty() {
+        return Err(StowSaveError::ContainsSpecialFiles(
+            path_to_save.to_string_lossy().into_owned(),
+            format_list(special_files),
+        )
+        .into());
+    }
+    Ok(())
+}
+
+/// Globs that leave nothing to save are most likely a mistake. A directory that is empty to
+/// begin with can still be saved.
+pub fn selection_is_not_empty(path_to_save: &Path, partition: &Partition) -> Result<()> {
+    if partition.selected == 0 && !partition.left_behind.is_empty() {
+        return Err(
+            StowSaveError::NothingSelected(path_to_save.to_string_lossy().into_owned()).into(),
+        );
+    }
+    Ok(())
+}
+
+/// A file that has more than one name.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Hardlink {
+    pub path: PathBuf,
+    pub links: u64,
+}
+
+impl fmt::Display for Hardlink {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} ({} links)", self.path.display(), self.links)
+    }
+}
+
+/// Find the non-directory entries at or below `path_to_save` whose link count is above one.
+///
+/// Replacing such a file with a symlink splits its hard link group: the other names keep the
+/// old inode and silently stop seeing changes.
+pub fn find_hardlinks(fs: &dyn Filesystem, path_to_save: &Path) -> io::Result<Vec<Hardlink>> {
+    let mut found = Vec::new();
+    find_hardlinks_in(fs, path_to_save, &mut found)?;
+    Ok(found)
+}
+
+fn find_hardlinks_in(
+    fs: &dyn Filesystem,
+    path: &Path,
+    found: &mut Vec<Hardlink>,
+) -> io::Result<()> {
+    let metadata = fs.symlink_metadata(path)?;
+    if metadata.is_dir() {
+        for entry in fs.read_dir(path)? {
+            find_hardlinks_in(fs, &entry, found)?;
+        }
+    } else if metadata.nlink > 1 {
+        found.push(Hardlink {
+            path: path.to_owned(),
+            links: metadata.nlink,
+        });
+    }
+    Ok(())
+}
+
+/// Hard linked files may only be saved once the user has agreed to split them.
+pub fn hardlinks_are_allowed(
+    path_to_save: &Path,
+    hardlinks: &[Hardlink],
+    allowed: bool,
+) -> Result<()> {
+    if !allowed && !hardlinks.is_empty() {
+        return Err(StowSaveError::ContainsHardlinks(
+            path_to_save.to_string_lossy().into_owned(),
+            format_list(hardlinks),
+        )
+        .into());
+    }
+    Ok(())
+}
+
+/// Likely secrets may only be saved once the user has agreed to put them in the package.
+pub fn secrets_are_allowed(path_to_save: &Path, findings: &[Finding], allowed: bool) -> Result<()> {
+    if !allowed && !findings.is_empty() {
+        return Err(StowSaveError::ContainsSecrets(
+            path_to_save.to_string_lossy().into_owned(),
+            format_list(findings),
+        )
+        .into());
+    }
+    Ok(())
+}
+
+/// Saves past the limits may only go ahead once the user has agreed to them.
+pub fn size_is_within_limits(
+    path_to_save: &Path,
+    usage: &Usage,
+    limits: Limits,
+    allowed: bool,
+) -> Result<()> {
+    let too_large = limits.max_size > 0 && usage.size > limits.max_size;
+    let too_many = limits.max_files > 0 && usage.files > limits.max_files;
+    if !allowed && (too_large || too_many) {
+        let max_size = match limits.max_size {
+            0 => "any size".to_string(),
+            size => usage::format_size(size),
+        };
+        let max_files = match limits.max_files {
+            0 => "any number of files".to_string(),
+            files => format!("{} files", files),
+        };
+        return Err(StowSaveError::TooLarge(
+            path_to_save.to_string_lossy().into_owned(),
+            format!("{} and {}", max_size, max_files),
+            usage.to_string(),
+        )
+        .into());
+    }
+    Ok(())
+}
+
+fn format_list<T: fmt::Display>(items: &[T]) -> String {
+    let lines: Vec<String> = items.iter().map(|item| format!("  {}", item)).collect();
+    lines.join("\n")
+}
+
 #[cfg(test)]
 mod tests {
-    use std::fs::File;
-    use std::os::unix::fs as unix_fs;
-
     use tempfile::TempDir;
 
     use super::*;
+    use crate::filesystem::{MemoryFs, RealFs};
+    use crate::selection::Selection;
+    use crate::stow::StowOptions;
 
     #[test]
-    fn test_stow_directory_is_grandchild_of_common_ancestor() {
-        let common_ancestor = Path::new("/home/user");
-
-        // bad case
-        let stow_dir = Path::new("/home/user/stow");
+    fn test_path_to_save_is_inside_stow_target() {
+        let stow_target = Path::new("/home/user");
         assert!(
-            stow_directory_is_grandchild_of_common_ancestor(stow_dir, common_ancestor).is_err()
+            path_to_save_is_inside_stow_target(Path::new("/home/user/.vimrc"), stow_target).is_ok()
         );
+        assert!(path_to_save_is_inside_stow_target(Path::new("/etc/hosts"), stow_target).is_err());
+    }
 
-        // good case
-        let stow_dir = Path::new("/home/user/dotfiles/stow");
-        assert!(stow_directory_is_grandchild_of_common_ancestor(stow_dir, common_ancestor).is_ok());
+    #[test]
+    fn test_path_to_save_is_not_ignored() {
+        let fs = MemoryFs::new();
+        fs.create_dir(Path::new("/vim")).unwrap();
+        let rules = IgnoreRules::load(&fs, Path::new("/vim"), &StowOptions::default()).unwrap();
+        let path = Path::new("/home/user/.vimrc");
+        assert!(path_to_save_is_not_ignored(path, Path::new(".vimrc"), &rules).is_ok());
+        let path = Path::new("/home/user/.gitignore");
+        assert!(path_to_save_is_not_ignored(path, Path::new(".gitignore"), &rules).is_err());
     }
 
     #[test]
     fn test_path_to_save_exists() {
-        let temp_dir = TempDir::new().unwrap();
-        let existing_file = temp_dir.path().join("existing_file");
-        File::create(&existing_file).unwrap();
+        let fs = MemoryFs::new();
+        fs.write(Path::new("/existing_file"), "").unwrap();
+        fs.symlink(Path::new("missing"), Path::new("/dangling"))
+            .unwrap();
 
-        assert!(path_to_save_exists(&existing_file).is_ok());
-        assert!(path_to_save_exists(&temp_dir.path().join("non_existent_file")).is_err());
+        assert!(path_to_save_exists(&fs, Path::new("/existing_file")).is_ok());
+        assert!(path_to_save_exists(&fs, Path::new("/dangling")).is_ok());
+        assert!(path_to_save_exists(&fs, Path::new("/non_existent_file")).is_err());
     }
 
     #[test]
     fn test_path_to_save_is_not_symlink() {
-        let temp_dir = TempDir::new().unwrap();
-        let regular_file = temp_dir.path().join("regular_file");
-        File::create(&regular_file).unwrap();
-
-        let symlink = temp_dir.path().join("symlink");
-        unix_fs::symlink(&regular_file, &symlink).unwrap();
+        let fs = MemoryFs::new();
+        let regular_file = Path::new("/regular_file");
+        fs.write(regular_file, "").unwrap();
+        let symlink = Path::new("/symlink");
+        fs.symlink(regular_file, symlink).unwrap();
 
-        assert!(path_to_save_is_not_symlink(&regular_file).is_ok());
-        assert!(path_to_save_is_not_symlink(&symlink).is_err());
+        assert!(path_to_save_is_not_symlink(&fs, regular_file).is_ok());
+        assert!(path_to_save_is_not_symlink(&fs, symlink).is_err());
     }
 
     #[test]
     fn test_stow_directory_exists() {
+        let fs = MemoryFs::new();
+        fs.create_dir(Path::new("/dotfiles")).unwrap();
+        fs.write(Path::new("/file"), "").unwrap();
+
+        assert!(stow_directory_exists(&fs, Path::new("/dotfiles")).is_ok());
+        assert!(stow_directory_exists(&fs, Path::new("/non_existent_dir")).is_err());
+        assert!(stow_directory_exists(&fs, Path::new("/file")).is_err());
+    }
+
+    #[test]
+    fn test_special_files_are_handled() {
         let temp_dir = TempDir::new().unwrap();
-        let non_existent_dir = temp_dir.path().join("non_existent_dir");
-        let file_path = temp_dir.path().join("file");
-        File::create(&file_path).unwrap();
+        let socket = temp_dir.path().join("socket");
+        let _listener = std::os::unix::net::UnixListener::bind(&socket).unwrap();
+        let special_files =
+            crate::copy::find_special_files(&RealFs, temp_dir.path(), &Default::default()).unwrap();
 
-        assert!(stow_directory_exists(temp_dir.path()).is_ok());
-        assert!(stow_directory_exists(&non_existent_dir).is_err());
-        assert!(stow_directory_exists(&file_path).is_err());
+        assert!(special_files_are_handled(temp_dir.path(), &[], SpecialFiles::Abort).is_ok());
+        assert!(
+            special_files_are_handled(temp_dir.path(), &special_files, SpecialFiles::Abort)
+                .is_err()
+        );
+        assert!(
+            special_files_are_handled(temp_dir.path(), &special_files, SpecialFiles::Exclude)
+                .is_ok()
+        );
+    }
+
+    #[test]
+    fn test_selection_is_not_empty() {
+        let fs = MemoryFs::new();
+        let dir = Path::new("/dir");
+        fs.create_dir(dir).unwrap();
+        fs.write(&dir.join("cache.bin"), "").unwrap();
+        let exclude = vec!["*.bin".to_string()];
+        let selection = Selection::new(SpecialFiles::Abort, &exclude, &[]).unwrap();
+        let partition = selection.partition(&fs, dir).unwrap();
+        // Only the directory itself is selected.
+        assert!(selection_is_not_empty(dir, &partition).is_err());
+
+        let selection = Selection::default();
+        let partition = selection.partition(&fs, dir).unwrap();
+        assert!(selection_is_not_empty(dir, &partition).is_ok());
+        let empty = Path::new("/empty");
+        fs.create_dir(empty).unwrap();
+        let partition = selection.partition(&fs, empty).unwrap();
+        assert!(selection_is_not_empty(empty, &partition).is_ok());
+
+        let include = vec!["*.toml".to_string()];
+        let selection = Selection::new(SpecialFiles::Abort, &[], &include).unwrap();
+        let partition = selection.partition(&fs, dir).unwrap();
+        assert!(selection_is_not_empty(dir, &partition).is_err());
+    }
+
+    #[test]
+    fn test_find_hardlinks() {
+        let fs = MemoryFs::new();
+        let dir = Path::new("/dir");
+        fs.create_dir(dir).unwrap();
+        fs.write(&dir.join("single"), "").unwrap();
+        fs.write(&dir.join("linked"), "").unwrap();
+        fs.hard_link(&dir.join("linked"), Path::new("/other_name"))
+            .unwrap();
+
+        let hardlinks = find_hardlinks(&fs, dir).unwrap();
+        assert_eq!(
+            hardlinks,
+            vec![Hardlink {
+                path: dir.join("linked"),
+                links: 2
+            }]
+        );
+        assert!(find_hardlinks(&fs, &dir.join("single")).unwrap().is_empty());
+
+        assert!(hardlinks_are_allowed(dir, &hardlinks, false).is_err());
+        assert!(hardlinks_are_allowed(dir, &hardlinks, true).is_ok());
+        assert!(hardlinks_are_allowed(dir, &[], false).is_ok());
+    }
+
+    #[test]
+    fn test_find_folded_ancestor() {
+        let fs = MemoryFs::new();
+        let home = Path::new("/home/user");
+        let stow_dir = home.join("dotfiles");
+        fs.create_dir_all(&stow_dir.join("nvim").join(".config").join("nvim"))
+            .unwrap();
+        fs.create_dir_all(&home.join("elsewhere")).unwrap();
+        fs.symlink(Path::new("dotfiles/nvim/.config"), &home.join(".config"))
+            .unwrap();
+        fs.symlink(Path::new("elsewhere"), &home.join("other"))
+            .unwrap();
+
+        assert_eq!(
+            find_folded_ancestor(&fs, &home.join(".config/nvim/init.lua"), &stow_dir),
+            Some(home.join(".config"))
+        );
+        assert_eq!(
+            find_folded_ancestor(&fs, &home.join("other/file"), &stow_dir),
+            None
+        );
+        assert_eq!(
+            find_folded_ancestor(&fs, &home.join(".vimrc"), &stow_dir),
+            None
+        );
+    }
+
+    #[test]
+    fn test_error_messages_show_non_utf8_paths_lossily() {
+        use std::os::unix::ffi::OsStrExt;
+
+        let path = Path::new("/tmp").join(std::ffi::OsStr::from_bytes(b"caf\xe9"));
+        let error = path_to_save_exists(&MemoryFs::new(), &path).unwrap_err();
+        assert!(error.to_string().contains("caf\u{FFFD}"));
+    }
+
+    #[test]
+    fn test_guardrails() {
+        let fs = MemoryFs::new();
+        let home = Path::new("/home/user");
+        let stow_dir = home.join("dotfiles");
+        fs.create_dir_all(&stow_dir.join("vim")).unwrap();
+        fs.symlink(home, Path::new("/home/link")).unwrap();
+        let vimrc = home.join(".vimrc");
+
+        assert!(path_to_save_is_not_root(Path::new("/")).is_err());
+        assert!(path_to_save_is_not_root(&vimrc).is_ok());
+
+        assert!(path_to_save_is_not_home(&fs, home, home).is_err());
+        // `$HOME` may be a symlink to the real home directory.
+        assert!(path_to_save_is_not_home(&fs, home, Path::new("/home/link")).is_err());
+        assert!(path_to_save_is_not_home(&fs, &vimrc, home).is_ok());
+
+        assert!(path_to_save_is_not_stow_directory(&fs, &stow_dir, &stow_dir).is_err());
+        assert!(path_to_save_is_not_stow_directory(&fs, &vimrc, &stow_dir).is_ok());
+
+        let inside = stow_dir.join("vim").join(".vimrc");
+        assert!(path_to_save_is_not_inside_stow_directory(&inside, &stow_dir).is_err());
+        assert!(path_to_save_is_not_inside_stow_directory(&vimrc, &stow_dir).is_ok());
+
+        assert!(path_to_save_is_not_ancestor_of_stow_directory(home, &stow_dir).is_err());
+        assert!(path_to_save_is_not_ancestor_of_stow_directory(&vimrc, &stow_dir).is_ok());
+    }
+
+    #[test]
+    fn test_path_to_save_is_not_denied() {
+        let fs = MemoryFs::new();
+        let deny_list = vec![PathBuf::from("/home/user/.cache")];
+
+        let cached = Path::new("/home/user/.cache/thumbnails");
+        assert!(path_to_save_is_not_denied(&fs, cached, &deny_list, false).is_err());
+        assert!(path_to_save_is_not_denied(&fs, cached, &deny_list, true).is_ok());
+        assert!(
+            path_to_save_is_not_denied(&fs, Path::new("/home/user/.cache"), &deny_list, false)
+                .is_err()
+        );
+        assert!(path_to_save_is_not_denied(
+            &fs,
+            Path::new("/home/user/.cachet"),
+            &deny_list,
+            false
+        )
+        .is_ok());
     }
 
     #[test]
     fn test_target_path_does_not_exist() {
-        let temp_dir = TempDir::new().unwrap();
-        let existing_file = temp_dir.path().join("existing_file");
-        File::create(&existing_file).unwrap();
+        let fs = MemoryFs::new();
+        fs.write(Path::new("/existing_file"), "").unwrap();
 
-        let non_existent_file = temp_dir.path().join("non_existent_file");
+        assert!(target_path_does_not_exist(&fs, Path::new("/non_existent_file")).is_ok());
+        assert!(target_path_does_not_exist(&fs, Path::new("/existing_file")).is_err());
+    }
+
+    #[test]
+    fn test_backup_path_does_not_exist() {
+        let fs = MemoryFs::new();
+        fs.symlink(Path::new("/gone"), Path::new("/.vimrc.bak"))
+            .unwrap();
 
-        assert!(target_path_does_not_exist(&non_existent_file).is_ok());
-        assert!(target_path_does_not_exist(&existing_file).is_err());
+        assert!(backup_path_does_not_exist(&fs, Path::new("/.zshrc.bak")).is_ok());
+        let error = crate::Error::from(
+            backup_path_does_not_exist(&fs, Path::new("/.vimrc.bak")).unwrap_err(),
+        );
+        assert_eq!(error.code(), "backup_already_exists");
+        assert_eq!(error.exit_code(), 4);
     }
 }
@@ -1,16 +1,34 @@
 //! This module contains the implementions for Commands that execute side effects to modify the
 //! filesytem.
-use std::fs;
-use std::path::PathBuf;
-use std::process::Command as ProcessCommand;
+use std::ffi::OsString;
+use std::io;
+use std::path::{Path, PathBuf};
 
-use anyhow::{Context, Result};
-use fs_extra::dir::CopyOptions;
+use anyhow::{anyhow, Context, Result};
+
+use crate::copy;
+use crate::filesystem::Filesystem;
+use crate::selection::Selection;
+use crate::stow::{self, StowOptions};
+use crate::transfer;
 
 /// Commands to execute side effects to modify the filesystem.
-#[derive(Debug)]
-pub(super) enum Command {
-    CreateDirIfNotExists(PathBuf),
+///
+/// Every command can [`describe`](Command::describe) itself, and most can produce their
+/// [`inverse`](Command::inverse), the command that undoes them.
+#[derive(Debug, Clone)]
+#[non_exhaustive]
+pub enum Command {
+    /// Create a directory and any missing parents.
+    CreateDirIfNotExists {
+        path: PathBuf,
+        /// A directory that corresponds to `path`, such as the directory a saved file comes from.
+        /// Each created directory copies the mode, and as root the ownership, of the matching
+        /// ancestor of `like`.
+        like: Option<PathBuf>,
+    },
+    /// Remove directories, innermost first, as long as they are empty.
+    RemoveEmptyDirs(Vec<PathBuf>),
     /// Move a file or directory into another directory.
     /// Error if `dir/dest_dir` already exists.
     MoveToDir {
@@ -18,108 +36,588 @@ pub(super) enum Command {
         from: PathBuf,
         /// The directory into which to move.
         dest_dir: PathBuf,
+        /// Which entries of `from` to take; the rest stay where they are.
+        selection: Selection,
     },
+    /// Move a file or directory to a new path, which must not exist yet.
+    MoveTo {
+        from: PathBuf,
+        to: PathBuf,
+        /// Which entries of `from` to take; the rest stay where they are.
+        selection: Selection,
+    },
+    /// Move a file or directory to a path that may already exist, merging directories that exist
+    /// in both places. Error if a file would replace another.
+    MoveInto { from: PathBuf, to: PathBuf },
+    /// Copy a file or directory to a new path, which must not exist yet.
+    CopyTo {
+        from: PathBuf,
+        to: PathBuf,
+        /// Which entries of `from` to take; the rest stay where they are.
+        selection: Selection,
+    },
+    /// Create a symlink at `path` pointing to `target`.
+    CreateSymlink { path: PathBuf, target: PathBuf },
+    /// Remove a symlink, leaving whatever it points to alone.
+    RemoveSymlink(PathBuf),
+    /// Remove a file, or a directory and everything in it.
+    RemovePath(PathBuf),
+    /// Replace a symlink to a directory, as created when stow folds a directory, with a real
+    /// directory containing one symlink per entry of the linked directory.
+    UnfoldDir(PathBuf),
+    /// Replace a directory that only contains symlinks with a symlink to `target`, the reverse
+    /// of [`Command::UnfoldDir`].
+    FoldDir { path: PathBuf, target: PathBuf },
     CreateBackup {
         original: PathBuf,
-        backup_name: String,
+        backup_name: OsString,
+        /// Which entries of `original` to take; the rest stay where they are.
+        selection: Selection,
     },
     RunStow {
+        /// The stow executable.
+        stow: PathBuf,
+        pwd: PathBuf,
+        package: OsString,
+        options: StowOptions,
+    },
+    /// Run `stow --delete` to remove the links to a package.
+    Unstow {
+        /// The stow executable.
+        stow: PathBuf,
         pwd: PathBuf,
-        package: String,
+        package: OsString,
+        options: StowOptions,
     },
 }
 
-pub(super) trait CommandImpl {
-    fn invoke(&self, verbose: bool) -> Result<()>;
+impl Command {
+    /// A one-line, human-readable description of what the command does.
+    pub fn describe(&self) -> String {
+        match self {
+            Command::CreateDirIfNotExists { path, .. } => {
+                format!("Create directory '{}'", path.display())
+            }
+            Command::RemoveEmptyDirs(dirs) => format!(
+                "Remove directories if empty: {}",
+                dirs.iter()
+                    .map(|dir| format!("'{}'", dir.display()))
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            ),
+            Command::MoveToDir { from, dest_dir, .. } => {
+                format!("Move '{}' into '{}'", from.display(), dest_dir.display())
+            }
+            Command::MoveTo { from, to, .. } => {
+                format!("Move '{}' to '{}'", from.display(), to.display())
+            }
+            Command::MoveInto { from, to } => {
+                format!("Merge '{}' into '{}'", from.display(), to.display())
+            }
+            Command::CopyTo { from, to, .. } => {
+                format!("Copy '{}' to '{}'", from.display(), to.display())
+            }
+            Command::CreateSymlink { path, target } => format!(
+                "Create symlink '{}' -> '{}'",
+                path.display(),
+                target.display()
+            ),
+            Command::RemoveSymlink(path) => format!("Remove symlink '{}'", path.display()),
+            Command::RemovePath(path) => format!("Remove '{}'", path.display()),
+            Command::UnfoldDir(dir) => format!("Unfold directory '{}'", dir.display()),
+            Command::FoldDir { path, target } => format!(
+                "Fold directory '{}' into a symlink to '{}'",
+                path.display(),
+                target.display()
+            ),
+            Command::CreateBackup {
+                original,
+                backup_name,
+                ..
+            } => format!(
+                "Back up '{}' as '{}'",
+                original.display(),
+                backup_name.to_string_lossy()
+            ),
+            Command::RunStow {
+                stow,
+                pwd,
+                package,
+                options,
+            } => format!(
+                "Run '{}' in '{}'",
+                stow_command_line(stow, &stow_args(options, package, false)),
+                pwd.display()
+            ),
+            Command::Unstow {
+                stow,
+                pwd,
+                package,
+                options,
+            } => format!(
+                "Run '{}' in '{}'",
+                stow_command_line(stow, &stow_args(options, package, true)),
+                pwd.display()
+            ),
+        }
+    }
+
+    /// The command that undoes this one, given `fs` as it is before this command runs. `None`
+    /// if the command changes nothing that can be restored, or if nothing would be changed.
+    ///
+    /// Moves are undone by moving everything back, including entries that the selection left
+    /// out, and the backups and copies a command makes are removed again.
+    pub fn inverse(&self, fs: &dyn Filesystem) -> Result<Option<Command>> {
+        let inverse = match self {
+            Command::CreateDirIfNotExists { path, .. } => {
+                // Only the directories this command is about to create are removed again.
+                let created: Vec<PathBuf> = path
+                    .ancestors()
+                    .take_while(|dir| fs.symlink_metadata(dir).is_err())
+                    .map(Path::to_owned)
+                    .collect();
+                if created.is_empty() {
+                    return Ok(None);
+                }
+                Command::RemoveEmptyDirs(created)
+            }
+            Command::MoveToDir { from, dest_dir, .. } => Command::MoveInto {
+                from: dest_dir.join(file_name(from)?),
+                to: from.clone(),
+            },
+            Command::MoveTo { from, to, .. } => Command::MoveInto {
+                from: to.clone(),
+                to: from.clone(),
+            },
+            Command::CopyTo { to, .. } => Command::RemovePath(to.clone()),
+            Command::CreateBackup {
+                original,
+                backup_name,
+                ..
+            } => {
+                // A backup that was there before isn't ours to remove.
+                let backup_path = original.with_file_name(backup_name);
+                if fs.symlink_metadata(&backup_path).is_ok() {
+                    return Ok(None);
+                }
+                Command::RemovePath(backup_path)
+            }
+            Command::CreateSymlink { path, .. } => Command::RemoveSymlink(path.clone()),
+            Command::RemoveSymlink(path) => Command::CreateSymlink {
+                path: path.clone(),
+                target: read_link(fs, path)?,
+            },
+            Command::UnfoldDir(dir) => Command::FoldDir {
+                path: dir.clone(),
+                target: read_link(fs, dir)?,
+            },
+            Command::FoldDir { path, .. } => Command::UnfoldDir(path.clone()),
+            Command::RunStow {
+                stow,
+                pwd,
+                package,
+                options,
+            } => Command::Unstow {
+                stow: stow.clone(),
+                pwd: pwd.clone(),
+                package: package.clone(),
+                options: options.clone(),
+            },
+            Command::Unstow {
+                stow,
+                pwd,
+                package,
+                options,
+            } => Command::RunStow {
+                stow: stow.clone(),
+                pwd: pwd.clone(),
+                package: package.clone(),
+                options: options.clone(),
+            },
+            Command::RemoveEmptyDirs(_) | Command::MoveInto { .. } | Command::RemovePath(_) => {
+                return Ok(None)
+            }
+        };
+        Ok(Some(inverse))
+    }
+
+    /// The paths the command looks at or changes, with what is below them. What stow does when
+    /// it runs isn't included.
+    pub(crate) fn paths(&self) -> Vec<PathBuf> {
+        match self {
+            Command::CreateDirIfNotExists { path, .. } => vec![path.clone()],
+            Command::RemoveEmptyDirs(dirs) => dirs.clone(),
+            Command::MoveToDir { from, dest_dir, .. } => vec![from.clone(), dest_dir.clone()],
+            Command::MoveTo { from, to, .. }
+            | Command::MoveInto { from, to }
+            | Command::CopyTo { from, to, .. } => vec![from.clone(), to.clone()],
+            Command::CreateSymlink { path, .. }
+            | Command::RemoveSymlink(path)
+            | Command::RemovePath(path)
+            | Command::UnfoldDir(path) => vec![path.clone()],
+            Command::FoldDir { path, target } => {
+                // The link target is relative to the directory the link is in.
+                let linked = path.parent().unwrap_or(path).join(target);
+                vec![path.clone(), path_clean::clean(linked)]
+            }
+            Command::CreateBackup {
+                original,
+                backup_name,
+                ..
+            } => vec![original.clone(), original.with_file_name(backup_name)],
+            Command::RunStow { .. } | Command::Unstow { .. } => Vec::new(),
+        }
+    }
+
+    /// The paths whose metadata, and that of the directories above them, the command reads
+    /// without looking below them.
+    pub(crate) fn shallow_paths(&self) -> Vec<PathBuf> {
+        match self {
+            Command::CreateDirIfNotExists { like, .. } => like.iter().cloned().collect(),
+            _ => Vec::new(),
+        }
+    }
+
+    /// The new path that a move or copy puts its result at. If it exists after the command
+    /// failed, the command may have been partly carried out.
+    pub(crate) fn created_path(&self) -> Option<PathBuf> {
+        match self {
+            Command::MoveToDir { from, dest_dir, .. } => Some(dest_dir.join(from.file_name()?)),
+            Command::MoveTo { to, .. } | Command::CopyTo { to, .. } => Some(to.clone()),
+            Command::CreateBackup {
+                original,
+                backup_name,
+                ..
+            } => Some(original.with_file_name(backup_name)),
+            _ => None,
+        }
+    }
+}
+
+pub trait CommandImpl {
+    /// Carry out the command on `fs`.
+    fn invoke(&self, fs: &dyn Filesystem) -> Result<()>;
 }
 
 impl CommandImpl for Command {
-    fn invoke(&self, verbose: bool) -> Result<()> {
+    fn invoke(&self, fs: &dyn Filesystem) -> Result<()> {
         match self {
-            Command::CreateDirIfNotExists(path) => {
-                if verbose {
-                    println!("Creating directory: '{}'", path.display());
+            Command::CreateDirIfNotExists { path, like } => {
+                if let Some(like) = like {
+                    create_dirs_like(fs, path, like).context("Failed to create directory")?;
+                }
+                fs.create_dir_all(path)
+                    .context("Failed to create directory")
+            }
+            Command::RemoveEmptyDirs(dirs) => {
+                remove_empty_dirs(fs, dirs).context("Failed to remove directory")
+            }
+            Command::MoveToDir {
+                from,
+                dest_dir,
+                selection,
+            } => {
+                let to = dest_dir.join(file_name(from)?);
+                transfer::move_path(fs, from, &to, selection)?;
+                relativize_links(fs, &to, from, selection)
+            }
+            Command::MoveTo {
+                from,
+                to,
+                selection,
+            } => {
+                transfer::move_path(fs, from, to, selection)?;
+                relativize_links(fs, to, from, selection)
+            }
+            Command::MoveInto { from, to } => transfer::merge_path(fs, from, to),
+            Command::CopyTo {
+                from,
+                to,
+                selection,
+            } => {
+                if fs.symlink_metadata(to).is_ok() {
+                    return Err(anyhow!("Destination '{}' already exists", to.display()));
                 }
-                fs::create_dir_all(path).context("Failed to create directory")
+                copy::copy_tree(fs, from, to, selection)
+                    .with_context(|| format!("Failed to copy '{}'", from.display()))?;
+                relativize_links(fs, to, from, selection)
             }
-            Command::MoveToDir { from, dest_dir } => {
-                if verbose {
-                    println!("Moving '{}' to '{}'", from.display(), dest_dir.display());
+            Command::CreateSymlink { path, target } => {
+                fs.symlink(target, path).context("Failed to create symlink")
+            }
+            Command::RemoveSymlink(path) => {
+                if !fs.is_symlink(path) {
+                    return Err(anyhow!("Path '{}' is not a symlink", path.display()));
                 }
-                fs_extra::move_items(&vec![from], dest_dir, &CopyOptions::new())?;
-                Ok(())
+                fs.remove_file(path).context("Failed to remove symlink")
+            }
+            Command::RemovePath(path) => {
+                let removed = match fs.symlink_metadata(path) {
+                    Ok(metadata) if metadata.is_dir() => fs.remove_dir_all(path),
+                    _ => fs.remove_file(path),
+                };
+                removed.with_context(|| format!("Failed to remove '{}'", path.display()))
             }
+            Command::UnfoldDir(dir) => {
+                unfold_dir(fs, dir).with_context(|| format!("Failed to unfold '{}'", dir.display()))
+            }
+            Command::FoldDir { path, target } => fold_dir(fs, path, target)
+                .with_context(|| format!("Failed to fold '{}'", path.display())),
             Command::CreateBackup {
                 original,
                 backup_name,
+                selection,
             } => {
-                if verbose {
-                    println!("Creating backup directory: '{}'", backup_name);
-                }
                 let backup_path = original.with_file_name(backup_name);
-                if original.is_file() {
-                    fs::copy(original, backup_path).context("Failed to create backup")?;
-                } else if original.is_dir() {
-                    fs_extra::dir::copy(
-                        original,
-                        backup_path,
-                        &CopyOptions::new().content_only(true),
-                    )?;
-                } else {
-                    return Err(anyhow::anyhow!("Path is not a file or directory"));
+                if !fs.is_file(original) && !fs.is_dir(original) {
+                    return Err(anyhow!("Path is not a file or directory"));
                 }
-                Ok(())
+                if fs.symlink_metadata(&backup_path).is_ok() {
+                    return Err(anyhow!("Backup '{}' already exists", backup_path.display()));
+                }
+                copy::copy_tree(fs, original, &backup_path, selection)
+                    .context("Failed to create backup")
             }
+            Command::RunStow {
+                stow,
+                pwd,
+                package,
+                options,
+            } => run_stow(fs, stow, pwd, package, &stow_args(options, package, false)),
+            Command::Unstow {
+                stow,
+                pwd,
+                package,
+                options,
+            } => run_stow(fs, stow, pwd, package, &stow_args(options, package, true)),
+        }
+    }
+}
 
-            Command::RunStow { pwd, package } => {
-                if verbose {
-                    println!(
-                        "Running 'stow {}' in directory '{}'",
-                        package,
-                        pwd.display()
-                    );
-                }
-                let output = ProcessCommand::new("stow")
-                    .arg(package)
-                    .current_dir(pwd)
-                    .output()
-                    .context("Failed to run stow command")?;
-
-                if verbose {
-                    println!("Stow stdout: {}", String::from_utf8_lossy(&output.stdout));
-                    println!("Stow stderr: {}", String::from_utf8_lossy(&output.stderr));
-                }
+fn file_name(path: &Path) -> Result<&std::ffi::OsStr> {
+    path.file_name()
+        .ok_or_else(|| anyhow!("Path '{}' has no file name", path.display()))
+}
 
-                if output.status.success() {
-                    Ok(())
-                } else {
-                    Err(anyhow::anyhow!(
-                        "Failed to run 'stow {}': {}",
-                        package,
-                        String::from_utf8_lossy(&output.stderr)
-                    ))
-                }
-            }
+fn read_link(fs: &dyn Filesystem, path: &Path) -> Result<PathBuf> {
+    fs.read_link(path)
+        .with_context(|| format!("Failed to read symlink '{}'", path.display()))
+}
+
+/// The arguments for stowing `package`, or for removing its links if `delete` is set.
+fn stow_args(options: &StowOptions, package: &OsString, delete: bool) -> Vec<OsString> {
+    let mut args = options.args();
+    if delete {
+        args.push("--delete".into());
+    }
+    args.push(package.clone());
+    args
+}
+
+fn stow_command_line(stow: &Path, args: &[OsString]) -> String {
+    let mut line = stow.display().to_string();
+    for arg in args {
+        line.push(' ');
+        line.push_str(&arg.to_string_lossy());
+    }
+    line
+}
+
+fn run_stow(
+    fs: &dyn Filesystem,
+    stow: &Path,
+    pwd: &Path,
+    package: &OsString,
+    args: &[OsString],
+) -> Result<()> {
+    let output = fs
+        .run(stow, args, Some(pwd))
+        .context("Failed to run stow command")?;
+
+    for line in String::from_utf8_lossy(&output.stdout)
+        .lines()
+        .chain(String::from_utf8_lossy(&output.stderr).lines())
+    {
+        log::debug!("stow: {}", line);
+    }
+
+    if output.status.success() {
+        Ok(())
+    } else {
+        Err(stow::parse_stderr(
+            &package.to_string_lossy(),
+            &String::from_utf8_lossy(&output.stderr),
+        )
+        .into())
+    }
+}
+
+/// Create the missing ancestors of `path`, and `path` itself, with the mode and ownership of
+/// the corresponding ancestors of `like`.
+fn create_dirs_like(fs: &dyn Filesystem, path: &Path, like: &Path) -> Result<()> {
+    let missing: Vec<(&Path, &Path)> = path
+        .ancestors()
+        .zip(like.ancestors())
+        .take_while(|(dir, _)| fs.symlink_metadata(dir).is_err())
+        .collect();
+    for (dir, source) in missing.into_iter().rev() {
+        let Ok(metadata) = fs.metadata(source) else {
+            // Nothing to copy from; the remaining directories get the default mode.
+            break;
+        };
+        fs.create_dir(dir)?;
+        fs.set_mode(dir, metadata.mode)?;
+        if fs.is_root() {
+            fs.set_owner(dir, metadata.uid, metadata.gid)?;
+        }
+    }
+    Ok(())
+}
+
+/// Remove `dirs`, which are listed innermost first, stopping at the first one that isn't empty
+/// since the ones that contain it aren't empty either.
+fn remove_empty_dirs(fs: &dyn Filesystem, dirs: &[PathBuf]) -> io::Result<()> {
+    for dir in dirs {
+        match fs.remove_dir(dir) {
+            Err(err) if err.kind() == io::ErrorKind::DirectoryNotEmpty => break,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => continue,
+            result => result?,
         }
     }
+    Ok(())
+}
+
+fn unfold_dir(fs: &dyn Filesystem, dir: &Path) -> Result<()> {
+    if !fs.is_symlink(dir) || !fs.is_dir(dir) {
+        return Err(anyhow::anyhow!(
+            "Path '{}' is not a symlink to a directory",
+            dir.display()
+        ));
+    }
+    let linked_dir = fs.canonicalize(dir)?;
+    // Links are made relative to where the directory physically is, like stow does.
+    let physical_dir = match (dir.parent(), dir.file_name()) {
+        (Some(parent), Some(name)) => fs.canonicalize(parent)?.join(name),
+        _ => return Err(anyhow::anyhow!("Path '{}' has no parent", dir.display())),
+    };
+    let link_target = fs.read_link(dir)?;
+    // Build the unfolded directory next to the link so that the link is only missing for the
+    // moment it takes to rename the new directory into place.
+    let mut temp_name = OsString::from(".");
+    temp_name.push(dir.file_name().unwrap());
+    temp_name.push(".stowsave-unfold");
+    let temp = dir.with_file_name(temp_name);
+    fs.create_dir(&temp)
+        .with_context(|| format!("Failed to create '{}'", temp.display()))?;
+    let filled = fill_unfolded_dir(fs, &temp, &linked_dir, &physical_dir)
+        .with_context(|| format!("Failed to fill '{}'", temp.display()))
+        .and_then(|()| {
+            fs.remove_file(dir)
+                .with_context(|| format!("Failed to remove symlink '{}'", dir.display()))
+        });
+    if let Err(err) = filled {
+        remove_unfolded_dir(fs, &temp);
+        return Err(err);
+    }
+    if let Err(err) = fs.rename(&temp, dir) {
+        // Put the folded directory back; the unfolded one is only thrown away once it is.
+        if fs.symlink(&link_target, dir).is_ok() {
+            remove_unfolded_dir(fs, &temp);
+        }
+        return Err(err).with_context(|| {
+            format!(
+                "Failed to rename '{}' to '{}'",
+                temp.display(),
+                dir.display()
+            )
+        });
+    }
+    Ok(())
+}
+
+/// Fill the new directory `temp` with links to the entries of `linked_dir`, relative to
+/// `physical_dir`, where `temp` ends up. The mode is set last, in case it is read-only.
+fn fill_unfolded_dir(
+    fs: &dyn Filesystem,
+    temp: &Path,
+    linked_dir: &Path,
+    physical_dir: &Path,
+) -> io::Result<()> {
+    for entry in fs.read_dir(linked_dir)? {
+        let target = pathdiff::diff_paths(&entry, physical_dir).unwrap_or(entry.clone());
+        fs.symlink(&target, &temp.join(entry.file_name().unwrap()))?;
+    }
+    fs.set_mode(temp, fs.metadata(linked_dir)?.mode)
+}
+
+/// Remove what is left of an unfold that failed, so that it can be tried again.
+fn remove_unfolded_dir(fs: &dyn Filesystem, temp: &Path) {
+    // The links can't be removed from a directory that already got a read-only mode.
+    let _ = fs.set_mode(temp, 0o700);
+    if let Err(err) = fs.remove_dir_all(temp) {
+        log::warn!("Failed to remove '{}': {}", temp.display(), err);
+    }
+}
+
+/// Replace the directory `dir` with a symlink to `target`. The directory may only contain
+/// symlinks, such as the ones [`unfold_dir`] creates, so that nothing but links is removed.
+fn fold_dir(fs: &dyn Filesystem, dir: &Path, target: &Path) -> Result<()> {
+    if fs.is_symlink(dir) || !fs.is_dir(dir) {
+        return Err(anyhow!("Path '{}' is not a directory", dir.display()));
+    }
+    let entries = fs.read_dir(dir)?;
+    if let Some(entry) = entries.iter().find(|entry| !fs.is_symlink(entry)) {
+        return Err(anyhow!(
+            "'{}' is not a symlink, so the directory can't be folded",
+            entry.display()
+        ));
+    }
+    for entry in entries {
+        fs.remove_file(&entry)?;
+    }
+    fs.remove_dir(dir)?;
+    fs.symlink(target, dir)?;
+    Ok(())
+}
+
+/// Rewrite absolute symlinks inside `to` that pointed into `from`, where the tree used to live,
+/// at entries that `selection` took along.
+fn relativize_links(
+    fs: &dyn Filesystem,
+    to: &Path,
+    from: &Path,
+    selection: &Selection,
+) -> Result<()> {
+    let rewritten = copy::relativize_links(fs, to, from, selection)
+        .context("Failed to rewrite absolute symlinks as relative ones")?;
+    for link in rewritten {
+        log::debug!("Rewrote symlink '{}' as a relative link", link.display());
+    }
+    Ok(())
 }
 
 #[cfg(test)]
 mod tests {
     use std::fs;
+    use std::os::unix::fs::{symlink, MetadataExt, PermissionsExt};
+    use std::process::Command as ProcessCommand;
 
     use tempfile::TempDir;
 
     use super::*;
+    use crate::filesystem::{MemoryFs, RealFs};
 
     #[test]
     fn test_create_directory() {
         let temp_dir = TempDir::new().unwrap();
         let new_dir = temp_dir.path().join("new_directory");
         let nested_dir = new_dir.join("nested");
-        Command::CreateDirIfNotExists(nested_dir.clone())
-            .invoke(true)
-            .unwrap();
+        Command::CreateDirIfNotExists {
+            path: nested_dir.clone(),
+            like: None,
+        }
+        .invoke(&RealFs)
+        .unwrap();
 
         assert!(nested_dir.is_dir());
     }
@@ -130,12 +628,41 @@ mod tests {
         let new_dir = temp_dir.path().join("new_directory");
         let nested_dir = new_dir.join("nested");
         fs::create_dir_all(&nested_dir).unwrap();
-        Command::CreateDirIfNotExists(nested_dir.clone())
-            .invoke(true)
-            .unwrap();
+        Command::CreateDirIfNotExists {
+            path: nested_dir.clone(),
+            like: None,
+        }
+        .invoke(&RealFs)
+        .unwrap();
         assert!(nested_dir.is_dir());
     }
 
+    #[test]
+    fn test_create_directory_like_source() {
+        let temp_dir = TempDir::new().unwrap();
+        let home = temp_dir.path().join("home");
+        let source = home.join(".ssh").join("keys");
+        fs::create_dir_all(&source).unwrap();
+        fs::set_permissions(home.join(".ssh"), fs::Permissions::from_mode(0o700)).unwrap();
+        fs::set_permissions(&source, fs::Permissions::from_mode(0o750)).unwrap();
+        let package = temp_dir.path().join("dotfiles").join("ssh");
+        fs::create_dir_all(&package).unwrap();
+        let package_mode = fs::metadata(&package).unwrap().mode();
+
+        Command::CreateDirIfNotExists {
+            path: package.join(".ssh").join("keys"),
+            like: Some(source),
+        }
+        .invoke(&RealFs)
+        .unwrap();
+
+        let mode = |path: PathBuf| fs::metadata(path).unwrap().mode() & 0o7777;
+        assert_eq!(mode(package.join(".ssh")), 0o700);
+        assert_eq!(mode(package.join(".ssh").join("keys")), 0o750);
+        // Existing directories are left alone.
+        assert_eq!(fs::metadata(&package).unwrap().mode(), package_mode);
+    }
+
     #[test]
     fn test_move_file() {
         let temp_dir = TempDir::new().unwrap();
@@ -152,8 +679,9 @@ mod tests {
         Command::MoveToDir {
             from: source.clone(),
             dest_dir: dest_dir.clone(),
+            selection: Selection::default(),
         }
-        .invoke(true)
+        .invoke(&RealFs)
         .unwrap();
 
         assert!(!source.exists());
@@ -179,8 +707,9 @@ mod tests {
         Command::MoveToDir {
             from: source_dir.clone(),
             dest_dir: destination_dir.clone(),
+            selection: Selection::default(),
         }
-        .invoke(true)
+        .invoke(&RealFs)
         .unwrap();
 
         assert!(!source_dir.exists());
@@ -188,6 +717,180 @@ mod tests {
         assert!(destination_dir.join("source_dir").join("file.txt").exists());
     }
 
+    #[test]
+    fn test_move_directory_keeps_internal_symlinks_working() {
+        let temp_dir = TempDir::new().unwrap();
+        let temp_path = temp_dir.path();
+        let source_dir = temp_path.join("foo");
+        fs::create_dir(&source_dir).unwrap();
+        fs::write(source_dir.join("config"), "test content").unwrap();
+        symlink(source_dir.join("config"), source_dir.join("absolute")).unwrap();
+        symlink("config", source_dir.join("relative")).unwrap();
+        let destination_dir = temp_path.join("pkg");
+        fs::create_dir(&destination_dir).unwrap();
+
+        Command::MoveToDir {
+            from: source_dir.clone(),
+            dest_dir: destination_dir.clone(),
+            selection: Selection::default(),
+        }
+        .invoke(&RealFs)
+        .unwrap();
+
+        let moved = destination_dir.join("foo");
+        for link in ["absolute", "relative"] {
+            assert!(moved.join(link).is_symlink());
+            assert_eq!(
+                fs::read_to_string(moved.join(link)).unwrap(),
+                "test content"
+            );
+        }
+        assert_eq!(
+            fs::read_link(moved.join("absolute")).unwrap(),
+            PathBuf::from("config")
+        );
+    }
+
+    #[test]
+    fn test_move_directory_keeps_links_to_entries_left_behind() {
+        let temp_dir = TempDir::new().unwrap();
+        let source_dir = temp_dir.path().join("app");
+        fs::create_dir_all(source_dir.join("cache")).unwrap();
+        fs::write(source_dir.join("cache/blob"), "cached").unwrap();
+        fs::write(source_dir.join("config"), "config").unwrap();
+        symlink(source_dir.join("cache/blob"), source_dir.join("blob")).unwrap();
+        symlink(source_dir.join("config"), source_dir.join("current")).unwrap();
+        let destination_dir = temp_dir.path().join("pkg");
+        fs::create_dir(&destination_dir).unwrap();
+
+        Command::MoveToDir {
+            from: source_dir.clone(),
+            dest_dir: destination_dir.clone(),
+            selection: Selection::new(Default::default(), &["cache".into()], &[]).unwrap(),
+        }
+        .invoke(&RealFs)
+        .unwrap();
+
+        let moved = destination_dir.join("app");
+        assert_eq!(
+            fs::read_link(moved.join("blob")).unwrap(),
+            source_dir.join("cache/blob")
+        );
+        assert_eq!(fs::read_to_string(moved.join("blob")).unwrap(), "cached");
+        assert_eq!(
+            fs::read_link(moved.join("current")).unwrap(),
+            PathBuf::from("config")
+        );
+    }
+
+    #[test]
+    fn test_copy_to() {
+        let temp_dir = TempDir::new().unwrap();
+        let source = temp_dir.path().join("bashrc");
+        fs::write(&source, "test content").unwrap();
+        let destination = temp_dir.path().join(".bashrc");
+
+        Command::CopyTo {
+            from: source.clone(),
+            to: destination.clone(),
+            selection: Selection::default(),
+        }
+        .invoke(&RealFs)
+        .unwrap();
+
+        assert_eq!(fs::read_to_string(&source).unwrap(), "test content");
+        assert_eq!(fs::read_to_string(&destination).unwrap(), "test content");
+
+        // The destination must not be overwritten.
+        let result = Command::CopyTo {
+            from: source.clone(),
+            to: destination.clone(),
+            selection: Selection::default(),
+        }
+        .invoke(&RealFs);
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn test_create_and_remove_symlink() {
+        let temp_dir = TempDir::new().unwrap();
+        let file = temp_dir.path().join("file");
+        fs::write(&file, "test content").unwrap();
+        let link = temp_dir.path().join("link");
+
+        Command::CreateSymlink {
+            path: link.clone(),
+            target: PathBuf::from("file"),
+        }
+        .invoke(&RealFs)
+        .unwrap();
+        assert_eq!(fs::read_link(&link).unwrap(), PathBuf::from("file"));
+
+        Command::RemoveSymlink(link.clone())
+            .invoke(&RealFs)
+            .unwrap();
+        assert!(link.symlink_metadata().is_err());
+        assert!(file.exists());
+
+        // Regular files are never removed by RemoveSymlink.
+        assert!(Command::RemoveSymlink(file.clone())
+            .invoke(&RealFs)
+            .is_err());
+        assert!(file.exists());
+    }
+
+    #[test]
+    fn test_unfold_dir() {
+        let temp_dir = TempDir::new().unwrap();
+        let home = temp_dir.path();
+        let package_dir = home.join("dotfiles").join("nvim").join(".config");
+        fs::create_dir_all(package_dir.join("nvim")).unwrap();
+        fs::write(package_dir.join("starship.toml"), "test content").unwrap();
+        let folded = home.join(".config");
+        symlink("dotfiles/nvim/.config", &folded).unwrap();
+
+        Command::UnfoldDir(folded.clone()).invoke(&RealFs).unwrap();
+
+        assert!(!folded.is_symlink());
+        assert!(folded.is_dir());
+        assert_eq!(
+            fs::read_link(folded.join("nvim")).unwrap(),
+            PathBuf::from("../dotfiles/nvim/.config/nvim")
+        );
+        assert_eq!(
+            fs::read_to_string(folded.join("starship.toml")).unwrap(),
+            "test content"
+        );
+        assert_eq!(fs::read_dir(&folded).unwrap().count(), 2);
+
+        // A real directory can't be unfolded.
+        assert!(Command::UnfoldDir(folded).invoke(&RealFs).is_err());
+    }
+
+    #[test]
+    fn test_unfold_dir_cleans_up_after_failure() {
+        let fs = MemoryFs::new();
+        let package_dir = Path::new("/home/user/dotfiles/nvim/.config");
+        fs.create_dir_all(&package_dir.join("nvim")).unwrap();
+        let folded = Path::new("/home/user/.config");
+        fs.symlink(Path::new("dotfiles/nvim/.config"), folded)
+            .unwrap();
+        fs.set_mode(package_dir, 0o300).unwrap();
+
+        let error = Command::UnfoldDir(folded.to_owned())
+            .invoke(&fs)
+            .unwrap_err();
+        assert!(format!("{:#}", error).contains("/home/user/..config.stowsave-unfold"));
+        assert!(!fs.exists(Path::new("/home/user/..config.stowsave-unfold")));
+        assert!(fs.is_symlink(folded));
+
+        // Trying again works, even for a read-only directory.
+        fs.set_mode(package_dir, 0o500).unwrap();
+        Command::UnfoldDir(folded.to_owned()).invoke(&fs).unwrap();
+        assert!(fs.is_symlink(&folded.join("nvim")));
+        assert_eq!(fs.metadata(folded).unwrap().mode, 0o500);
+    }
+
     #[test]
     fn test_move_to_dir_error_if_target_already_exists() {
         // Create source.txt and dest_dir/source.txt
@@ -204,8 +907,9 @@ mod tests {
         let result = Command::MoveToDir {
             from: source.clone(),
             dest_dir: dest_dir.clone(),
+            selection: Selection::default(),
         }
-        .invoke(true);
+        .invoke(&RealFs);
 
         // Check that the error message is correct
         assert!(result.is_err());
@@ -229,9 +933,10 @@ mod tests {
 
         Command::CreateBackup {
             original: source.clone(),
-            backup_name: backup_name.to_string(),
+            backup_name: backup_name.into(),
+            selection: Selection::default(),
         }
-        .invoke(true)
+        .invoke(&RealFs)
         .unwrap();
 
         let backup_path = source.with_file_name(backup_name);
@@ -259,9 +964,10 @@ mod tests {
 
         Command::CreateBackup {
             original: source_dir.clone(),
-            backup_name: backup_name.to_string(),
+            backup_name: backup_name.into(),
+            selection: Selection::default(),
         }
-        .invoke(true)
+        .invoke(&RealFs)
         .unwrap();
 
         let backup_dir = temp_path.join(backup_name);
@@ -285,6 +991,43 @@ mod tests {
         assert_eq!(original_content, backup_content);
     }
 
+    #[test]
+    fn test_create_backup_non_utf8_name() {
+        use std::os::unix::ffi::OsStrExt;
+
+        let temp_dir = TempDir::new().unwrap();
+        let name = std::ffi::OsStr::from_bytes(b"caf\xe9.conf");
+        let source = temp_dir.path().join(name);
+        fs::write(&source, "test content").unwrap();
+        let mut backup_name = name.to_owned();
+        backup_name.push(".bak");
+
+        Command::CreateBackup {
+            original: source.clone(),
+            backup_name: backup_name.clone(),
+            selection: Selection::default(),
+        }
+        .invoke(&RealFs)
+        .unwrap();
+
+        let backup_path = temp_dir.path().join(&backup_name);
+        assert_eq!(fs::read_to_string(backup_path).unwrap(), "test content");
+
+        let dest_dir = temp_dir.path().join("dest_dir");
+        fs::create_dir(&dest_dir).unwrap();
+        Command::MoveToDir {
+            from: source.clone(),
+            dest_dir: dest_dir.clone(),
+            selection: Selection::default(),
+        }
+        .invoke(&RealFs)
+        .unwrap();
+        assert_eq!(
+            fs::read_to_string(dest_dir.join(name)).unwrap(),
+            "test content"
+        );
+    }
+
     #[test]
     fn test_run_stow() {
         // Check if stow is available
@@ -311,10 +1054,12 @@ mod tests {
 
         // Run stow command
         let run_stow_command = Command::RunStow {
+            stow: "stow".into(),
             pwd: parent_dir.clone(),
-            package: "stow_dir".to_string(),
+            package: "stow_dir".into(),
+            options: StowOptions::default(),
         };
-        run_stow_command.invoke(true).unwrap();
+        run_stow_command.invoke(&RealFs).unwrap();
 
         // Verify that the symlink has been created
         let symlink_path = temp_path.join("tmp_file.txt");
@@ -328,4 +1073,163 @@ mod tests {
             file_in_stow_dir.canonicalize().unwrap()
         );
     }
+
+    #[test]
+    fn test_run_stow_in_memory() {
+        let fs = MemoryFs::new();
+        Command::RunStow {
+            stow: "/usr/bin/stow".into(),
+            pwd: "/home/user/dotfiles".into(),
+            package: "vim".into(),
+            options: StowOptions {
+                no_folding: true,
+                ..StowOptions::default()
+            },
+        }
+        .invoke(&fs)
+        .unwrap();
+
+        let runs = fs.runs();
+        assert_eq!(runs.len(), 1);
+        assert_eq!(runs[0].program, PathBuf::from("/usr/bin/stow"));
+        assert_eq!(runs[0].args, vec!["--no-folding", "vim"]);
+        assert_eq!(runs[0].dir, Some(PathBuf::from("/home/user/dotfiles")));
+    }
+
+    #[test]
+    fn test_describe() {
+        let command = Command::MoveTo {
+            from: "/home/user/.vimrc".into(),
+            to: "/home/user/dotfiles/vim/dot-vimrc".into(),
+            selection: Selection::default(),
+        };
+        assert_eq!(
+            command.describe(),
+            "Move '/home/user/.vimrc' to '/home/user/dotfiles/vim/dot-vimrc'"
+        );
+        let command = Command::RunStow {
+            stow: "stow".into(),
+            pwd: "/home/user/dotfiles".into(),
+            package: "vim".into(),
+            options: StowOptions {
+                no_folding: true,
+                ..StowOptions::default()
+            },
+        };
+        assert_eq!(
+            command.describe(),
+            "Run 'stow --no-folding vim' in '/home/user/dotfiles'"
+        );
+        assert_eq!(
+            command
+                .inverse(&MemoryFs::new())
+                .unwrap()
+                .unwrap()
+                .describe(),
+            "Run 'stow --no-folding --delete vim' in '/home/user/dotfiles'"
+        );
+    }
+
+    /// Every entry below `/`, with file contents and symlink targets.
+    fn snapshot(fs: &MemoryFs) -> Vec<(PathBuf, String)> {
+        let mut entries = Vec::new();
+        let mut pending = vec![PathBuf::from("/")];
+        while let Some(path) = pending.pop() {
+            let metadata = fs.symlink_metadata(&path).unwrap();
+            let contents = if metadata.is_dir() {
+                pending.extend(fs.read_dir(&path).unwrap());
+                format!("dir {:o}", metadata.mode)
+            } else if metadata.is_symlink() {
+                format!("-> {}", fs.read_link(&path).unwrap().display())
+            } else {
+                String::from_utf8(fs.read(&path).unwrap()).unwrap()
+            };
+            entries.push((path, contents));
+        }
+        entries.sort();
+        entries
+    }
+
+    #[test]
+    fn test_inverse_undoes_commands() {
+        let fs = MemoryFs::new();
+        let home = Path::new("/home/user");
+        fs.create_dir_all(&home.join(".config/nvim")).unwrap();
+        fs.write(&home.join(".config/nvim/init.lua"), "init")
+            .unwrap();
+        fs.create_dir_all(&home.join("dotfiles/tmux/.config/tmux"))
+            .unwrap();
+        fs.symlink(Path::new("dotfiles/tmux/.config/tmux"), &home.join(".tmux"))
+            .unwrap();
+        let before = snapshot(&fs);
+        let package = home.join("dotfiles/nvim/.config");
+        let commands = vec![
+            Command::CreateBackup {
+                original: home.join(".config/nvim"),
+                backup_name: "nvim.bak".into(),
+                selection: Selection::default(),
+            },
+            Command::CreateDirIfNotExists {
+                path: package.clone(),
+                like: Some(home.join(".config")),
+            },
+            Command::MoveToDir {
+                from: home.join(".config/nvim"),
+                dest_dir: package.clone(),
+                selection: Selection::default(),
+            },
+            Command::CopyTo {
+                from: package.join("nvim"),
+                to: home.join("copy"),
+                selection: Selection::default(),
+            },
+            Command::CreateSymlink {
+                path: home.join(".config/nvim"),
+                target: "../dotfiles/nvim/.config/nvim".into(),
+            },
+            Command::UnfoldDir(home.join(".tmux")),
+            Command::RemoveSymlink(home.join(".config/nvim")),
+        ];
+
+        let mut inverses = Vec::new();
+        for command in &commands {
+            inverses.extend(command.inverse(&fs).unwrap());
+            command.invoke(&fs).unwrap();
+        }
+        assert_ne!(snapshot(&fs), before);
+        for inverse in inverses.iter().rev() {
+            inverse.invoke(&fs).unwrap();
+        }
+
+        assert_eq!(snapshot(&fs), before);
+    }
+
+    #[test]
+    fn test_backup_keeps_existing_backup() {
+        let fs = MemoryFs::new();
+        fs.write(Path::new("/.vimrc"), "new").unwrap();
+        fs.write(Path::new("/.vimrc.bak"), "old").unwrap();
+        let backup = Command::CreateBackup {
+            original: "/.vimrc".into(),
+            backup_name: ".vimrc.bak".into(),
+            selection: Selection::default(),
+        };
+
+        assert!(backup.inverse(&fs).unwrap().is_none());
+        assert!(backup.invoke(&fs).is_err());
+        assert_eq!(fs.read(Path::new("/.vimrc.bak")).unwrap(), b"old");
+    }
+
+    #[test]
+    fn test_fold_dir_refuses_real_files() {
+        let fs = MemoryFs::new();
+        fs.create_dir(Path::new("/dir")).unwrap();
+        fs.write(Path::new("/dir/file"), "").unwrap();
+        let fold = Command::FoldDir {
+            path: "/dir".into(),
+            target: "/elsewhere".into(),
+        };
+        assert!(fold.invoke(&fs).is_err());
+        assert!(fs.is_file(Path::new("/dir/file")));
+    }
 }
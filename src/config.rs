@@ -0,0 +1,268 @@
+//! User configuration, read from `~/.config/stowsave/config.toml` or the file given with
+//! `--config`.
+//!
+//! ```toml
+//! # Paths that can't be saved without --force. Replaces the built-in list.
+//! deny = ["~/.cache", "~/Downloads"]
+//!
+//! # How much a save may put into a package before stowsave asks first. 0 turns a limit off.
+//! [limits]
+//! max_size = "50 MB"
+//! max_files = 5000
+//!
+//! # Options passed to stow for every package.
+//! [stow]
+//! ignore = ['\.swp']
+//!
+//! # Options passed to stow for the package named `nvim`, in addition to the ones above.
+//! [packages.nvim]
+//! no_folding = true
+//! ```
+//!
+//! The stow options are `ignore`, `defer` and `override` (lists of regexes), `adopt` and
+//! `no_folding` and `dotfiles` (booleans) and `verbose` (a number).
+//!
+//! Sizes are a number of bytes or a string with a unit, like `"500 KB"`, `"10MB"` or `"1 GiB"`.
+//! The limits default to 10 MB and 1000 files.
+use std::collections::BTreeMap;
+use std::env;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use anyhow::{bail, Context, Result};
+use serde::{Deserialize, Deserializer};
+
+use crate::filesystem::RealFs;
+use crate::stow::StowOptions;
+use crate::util;
+
+/// Paths that are never worth putting into a dotfiles repository.
+const DEFAULT_DENY: &[&str] = &[
+    "~/.cache",
+    "~/.local/share/Trash",
+    "/proc",
+    "/sys",
+    "/dev",
+    "/run",
+];
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    /// Paths that can't be saved, nor anything inside them, unless `--force` is given.
+    deny: Option<Vec<String>>,
+    /// Thresholds for the size of a save.
+    limits: Limits,
+    /// Options passed to stow for every package.
+    stow: StowConfig,
+    /// Options passed to stow for individual packages, keyed by package name.
+    packages: BTreeMap<String, StowConfig>,
+}
+
+/// How much a save may put into a package before the user has to agree to it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Limits {
+    /// The total size of the saved files in bytes, or 0 for no limit.
+    #[serde(deserialize_with = "deserialize_size")]
+    pub max_size: u64,
+    /// The number of saved files, or 0 for no limit.
+    pub max_files: usize,
+}
+
+impl Default for Limits {
+    fn default() -> Limits {
+        Limits {
+            max_size: 10_000_000,
+            max_files: 1000,
+        }
+    }
+}
+
+/// Options passed to stow.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct StowConfig {
+    ignore: Vec<String>,
+    defer: Vec<String>,
+    #[serde(rename = "override")]
+    overrides: Vec<String>,
+    adopt: bool,
+    no_folding: bool,
+    dotfiles: bool,
+    verbose: u8,
+}
+
+impl From<StowConfig> for StowOptions {
+    fn from(config: StowConfig) -> StowOptions {
+        StowOptions {
+            ignore: config.ignore,
+            defer: config.defer,
+            overrides: config.overrides,
+            adopt: config.adopt,
+            no_folding: config.no_folding,
+            dotfiles: config.dotfiles,
+            verbose: config.verbose,
+            ..StowOptions::default()
+        }
+    }
+}
+
+impl Config {
+    /// Read the config file at `path`, or the default config file if there is one.
+    pub fn load(path: Option<&Path>) -> Result<Config> {
+        let path = match path {
+            Some(path) => path.to_owned(),
+            None => match default_path() {
+                Some(path) if path.is_file() => path,
+                _ => return Ok(Config::default()),
+            },
+        };
+        let contents = fs::read_to_string(&path)
+            .with_context(|| format!("Failed to read config file '{}'", path.display()))?;
+        Config::parse(&contents)
+            .with_context(|| format!("Failed to parse config file '{}'", path.display()))
+    }
+
+    fn parse(contents: &str) -> Result<Config> {
+        Ok(toml::from_str(contents)?)
+    }
+
+    /// The deny-list with `~` expanded to `home`.
+    pub fn deny_list(&self, home: Option<&Path>) -> Vec<PathBuf> {
+        match &self.deny {
+            Some(deny) => deny
+                .iter()
+                .map(|path| util::expand_tilde(path, home))
+                .collect(),
+            None => DEFAULT_DENY
+                .iter()
+                .map(|path| util::expand_tilde(path, home))
+                .collect(),
+        }
+    }
+
+    pub fn limits(&self) -> Limits {
+        self.limits
+    }
+
+    /// The options to pass to stow for the package called `name`.
+    pub fn stow_options(&self, name: &str) -> StowOptions {
+        let mut options = StowOptions::from(self.stow.clone());
+        if let Some(package) = self.packages.get(name) {
+            options.merge(package.clone().into());
+        }
+        options
+    }
+}
+
+fn deserialize_size<'de, D: Deserializer<'de>>(deserializer: D) -> Result<u64, D::Error> {
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum Size {
+        Bytes(u64),
+        Text(String),
+    }
+    match Size::deserialize(deserializer)? {
+        Size::Bytes(bytes) => Ok(bytes),
+        Size::Text(text) => parse_size(&text).map_err(serde::de::Error::custom),
+    }
+}
+
+/// Parse a size like `10MB` or `1.5 GiB` into bytes.
+fn parse_size(text: &str) -> Result<u64> {
+    let text = text.trim();
+    let split = text
+        .find(|c: char| !c.is_ascii_digit() && c != '.')
+        .unwrap_or(text.len());
+    let (number, unit) = text.split_at(split);
+    let number: f64 = number
+        .parse()
+        .with_context(|| format!("Invalid size '{}'", text))?;
+    let factor: u64 = match unit.trim().to_ascii_lowercase().as_str() {
+        "" | "b" => 1,
+        "k" | "kb" => 1000,
+        "m" | "mb" => 1000 * 1000,
+        "g" | "gb" => 1000 * 1000 * 1000,
+        "kib" => 1 << 10,
+        "mib" => 1 << 20,
+        "gib" => 1 << 30,
+        _ => bail!(
+            "Invalid size '{}', expected a unit like KB, MB or GiB",
+            text
+        ),
+    };
+    Ok((number * factor as f64) as u64)
+}
+
+fn default_path() -> Option<PathBuf> {
+    let config_dir = match env::var_os("XDG_CONFIG_HOME") {
+        Some(dir) if !dir.is_empty() => PathBuf::from(dir),
+        _ => util::home_dir(&RealFs)?.join(".config"),
+    };
+    Some(config_dir.join("stowsave").join("config.toml"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_default_deny_list() {
+        let deny_list = Config::parse("").unwrap().deny_list(None);
+        assert!(deny_list.contains(&PathBuf::from("/proc")));
+        assert_eq!(deny_list.len(), DEFAULT_DENY.len());
+    }
+
+    #[test]
+    fn test_deny_list_replaces_default() {
+        let config = Config::parse(r#"deny = ["/srv", "~/Downloads"]"#).unwrap();
+        assert_eq!(
+            config.deny_list(Some(Path::new("/home/user"))),
+            vec![PathBuf::from("/srv"), PathBuf::from("/home/user/Downloads")]
+        );
+    }
+
+    #[test]
+    fn test_stow_options() {
+        let config = Config::parse(
+            "[stow]\nignore = ['\\.swp']\n[packages.nvim]\nno_folding = true\noverride = ['bin']",
+        )
+        .unwrap();
+        let nvim = config.stow_options("nvim");
+        assert!(nvim.no_folding);
+        assert_eq!(nvim.ignore, vec![r"\.swp"]);
+        assert_eq!(nvim.overrides, vec!["bin"]);
+        let vim = config.stow_options("vim");
+        assert!(!vim.no_folding);
+        assert_eq!(vim.ignore, vec![r"\.swp"]);
+        assert!(Config::parse("[packages.nvim]\nfolding = false").is_err());
+    }
+
+    #[test]
+    fn test_limits() {
+        assert_eq!(Config::parse("").unwrap().limits(), Limits::default());
+        let config = Config::parse("[limits]\nmax_size = \"1.5 MiB\"\nmax_files = 0").unwrap();
+        assert_eq!(
+            config.limits(),
+            Limits {
+                max_size: 1_572_864,
+                max_files: 0
+            }
+        );
+        let config = Config::parse("[limits]\nmax_size = 2000").unwrap();
+        assert_eq!(config.limits().max_size, 2000);
+        assert_eq!(parse_size("10MB").unwrap(), 10_000_000);
+        assert!(Config::parse("[limits]\nmax_size = \"10 parsecs\"").is_err());
+    }
+
+    #[test]
+    fn test_unknown_keys_are_rejected() {
+        assert!(Config::parse("deny_list = []").is_err());
+    }
+
+    #[test]
+    fn test_load_missing_file() {
+        assert!(Config::load(Some(Path::new("/nonexistent/config.toml"))).is_err());
+    }
+}
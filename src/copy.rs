@@ -0,0 +1,536 @@
+//! A copy engine that produces faithful copies of files and directory trees.
+//!
+//! Besides contents, every copied entry keeps its permission bits, ownership, access and
+//! modification times and extended attributes. Symlinks are recreated rather than followed.
+//! Special files (sockets, FIFOs and device nodes) are handled according to [`SpecialFiles`].
+use std::fmt;
+use std::io;
+use std::os::unix::ffi::OsStrExt;
+use std::path::{Path, PathBuf};
+
+use clap::ValueEnum;
+
+use crate::filesystem::{FileType, Filesystem, Metadata};
+use crate::selection::{Selection, Visit};
+
+/// What to do with special files found inside a tree that is being saved.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, ValueEnum)]
+pub enum SpecialFiles {
+    /// Refuse to copy or move a tree that contains special files.
+    #[default]
+    Abort,
+    /// Leave special files where they are and copy or move everything else.
+    Exclude,
+    /// Recreate FIFOs in the copy and leave the other special files where they are.
+    Recreate,
+}
+
+impl SpecialFiles {
+    /// Whether an entry of this type is left out of copies and moves.
+    pub fn excludes(self, file_type: FileType) -> bool {
+        match self {
+            SpecialFiles::Abort => false,
+            SpecialFiles::Exclude => file_type.is_special(),
+            SpecialFiles::Recreate => file_type.is_special() && !file_type.is_fifo(),
+        }
+    }
+}
+
+/// A socket, FIFO or device node found inside a tree.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SpecialFile {
+    pub path: PathBuf,
+    pub file_type: FileType,
+}
+
+impl SpecialFile {
+    pub fn kind(&self) -> &'static str {
+        match self.file_type {
+            FileType::Socket => "socket",
+            FileType::Fifo => "FIFO",
+            FileType::CharDevice => "character device",
+            _ => "block device",
+        }
+    }
+}
+
+impl fmt::Display for SpecialFile {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} ({})", self.path.display(), self.kind())
+    }
+}
+
+/// List every special file at or below `root` that `selection`'s globs don't leave out, without
+/// following symlinks.
+pub fn find_special_files(
+    fs: &dyn Filesystem,
+    root: &Path,
+    selection: &Selection,
+) -> io::Result<Vec<SpecialFile>> {
+    let mut found = Vec::new();
+    find_special_files_in(
+        fs,
+        root,
+        Path::new(""),
+        Visit::Descend,
+        selection,
+        &mut found,
+    )?;
+    Ok(found)
+}
+
+fn find_special_files_in(
+    fs: &dyn Filesystem,
+    path: &Path,
+    relative: &Path,
+    parent: Visit,
+    selection: &Selection,
+    found: &mut Vec<SpecialFile>,
+) -> io::Result<()> {
+    let file_type = fs.symlink_metadata(path)?.file_type;
+    let visit = selection.visit_patterns(relative, file_type, parent);
+    if visit == Visit::Skip {
+        return Ok(());
+    }
+    if file_type.is_dir() {
+        for entry in fs.read_dir(path)? {
+            let child_relative = relative.join(entry.file_name().unwrap());
+            find_special_files_in(fs, &entry, &child_relative, visit, selection, found)?;
+        }
+    } else if file_type.is_special() {
+        found.push(SpecialFile {
+            path: path.to_owned(),
+            file_type,
+        });
+    }
+    Ok(())
+}
+
+/// Recursively copy the entries of `from` that `selection` takes to `to`, preserving metadata.
+/// `to` must not exist.
+///
+/// Special files are skipped or recreated as the selection's [`SpecialFiles`] policy says; with
+/// [`SpecialFiles::Abort`] the copy fails when it reaches one. Directories that are only there
+/// to hold selected entries are left out when they end up holding none.
+pub(super) fn copy_tree(
+    fs: &dyn Filesystem,
+    from: &Path,
+    to: &Path,
+    selection: &Selection,
+) -> io::Result<()> {
+    copy_entry(fs, from, to, Path::new(""), Visit::Descend, selection).map(|_| ())
+}
+
+/// Copy one entry and what's below it. Returns whether anything was created at `to`.
+fn copy_entry(
+    fs: &dyn Filesystem,
+    from: &Path,
+    to: &Path,
+    relative: &Path,
+    parent: Visit,
+    selection: &Selection,
+) -> io::Result<bool> {
+    let metadata = fs.symlink_metadata(from)?;
+    let file_type = metadata.file_type;
+    let visit = selection.visit(relative, file_type, parent);
+    if visit == Visit::Skip {
+        return Ok(false);
+    }
+    if file_type.is_dir() {
+        fs.create_dir(to)?;
+        let mut copied_any = false;
+        for entry in fs.read_dir(from)? {
+            let name = entry.file_name().unwrap();
+            let child_relative = relative.join(name);
+            copied_any |= copy_entry(
+                fs,
+                &entry,
+                &to.join(name),
+                &child_relative,
+                visit,
+                selection,
+            )?;
+        }
+        if visit == Visit::Descend && !copied_any {
+            fs.remove_dir(to)?;
+            return Ok(false);
+        }
+    } else if visit == Visit::Descend {
+        return Ok(false);
+    } else if file_type.is_symlink() {
+        fs.symlink(&fs.read_link(from)?, to)?;
+    } else if file_type.is_file() {
+        fs.copy_file(from, to)?;
+    } else if file_type.is_fifo() && selection.special_files == SpecialFiles::Recreate {
+        fs.mkfifo(to)?;
+    } else {
+        return Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            format!("'{}' is not a file, directory or symlink", from.display()),
+        ));
+    }
+    // Directories get their metadata last so that a read-only mode or the mtime isn't
+    // disturbed by creating their children.
+    copy_metadata(fs, from, to, &metadata)?;
+    Ok(true)
+}
+
+/// Copy ownership, extended attributes, permissions and timestamps from `from` to `to`.
+fn copy_metadata(
+    fs: &dyn Filesystem,
+    from: &Path,
+    to: &Path,
+    metadata: &Metadata,
+) -> io::Result<()> {
+    copy_ownership(fs, to, metadata)?;
+    copy_xattrs(fs, from, to)?;
+    // Symlink permissions are meaningless on Linux and can't be changed.
+    if !metadata.is_symlink() {
+        fs.set_mode(to, metadata.mode)?;
+    }
+    fs.set_times(to, metadata)
+}
+
+fn copy_ownership(fs: &dyn Filesystem, to: &Path, metadata: &Metadata) -> io::Result<()> {
+    match fs.set_owner(to, metadata.uid, metadata.gid) {
+        // Only root may give files away, so an unprivileged user keeps the files they copy.
+        Err(err) if err.kind() == io::ErrorKind::PermissionDenied && !fs.is_root() => Ok(()),
+        result => result,
+    }
+}
+
+fn copy_xattrs(fs: &dyn Filesystem, from: &Path, to: &Path) -> io::Result<()> {
+    for name in fs.list_xattrs(from)? {
+        let Some(value) = fs.get_xattr(from, &name)? else {
+            continue;
+        };
+        match fs.set_xattr(to, &name, &value) {
+            // Attributes outside the user namespace (security labels, ACLs, ...) are managed by
+            // the system and may legitimately be refused.
+            Err(_) if !name.as_bytes().starts_with(b"user.") => {}
+            result => result?,
+        }
+    }
+    Ok(())
+}
+
+/// Rewrite absolute symlinks below `root` that point into `old_root` as relative links.
+///
+/// `root` is a tree that used to live at `old_root`, and got there by moving the entries that
+/// `selection` takes. Absolute links between its entries would keep pointing at the old
+/// location, while relative ones resolve wherever the tree lives. Links to entries that the
+/// selection left at the old location keep pointing there. Returns the links that were
+/// rewritten.
+pub(super) fn relativize_links(
+    fs: &dyn Filesystem,
+    root: &Path,
+    old_root: &Path,
+    selection: &Selection,
+) -> io::Result<Vec<PathBuf>> {
+    let mut rewritten = Vec::new();
+    relativize_links_in(fs, root, root, old_root, selection, &mut rewritten)?;
+    Ok(rewritten)
+}
+
+fn relativize_links_in(
+    fs: &dyn Filesystem,
+    path: &Path,
+    root: &Path,
+    old_root: &Path,
+    selection: &Selection,
+    rewritten: &mut Vec<PathBuf>,
+) -> io::Result<()> {
+    let metadata = fs.symlink_metadata(path)?;
+    if metadata.is_dir() {
+        for entry in fs.read_dir(path)? {
+            relativize_links_in(fs, &entry, root, old_root, selection, rewritten)?;
+        }
+        return Ok(());
+    }
+    if !metadata.is_symlink() {
+        return Ok(());
+    }
+    let target = fs.read_link(path)?;
+    if !target.is_absolute() || !target.starts_with(old_root) {
+        return Ok(());
+    }
+    if !was_moved(fs, root, old_root, &target, selection) {
+        return Ok(());
+    }
+    // Where the link used to live, so that the relative target is computed within the old tree.
+    let old_path = old_root.join(path.strip_prefix(root).unwrap_or(path));
+    let old_parent = old_path.parent().unwrap_or(old_root);
+    let Some(mut relative_target) = pathdiff::diff_paths(&target, old_parent) else {
+        return Ok(());
+    };
+    if relative_target.as_os_str().is_empty() {
+        relative_target.push(".");
+    }
+    fs.remove_file(path)?;
+    fs.symlink(&relative_target, path)?;
+    copy_ownership(fs, path, &metadata)?;
+    fs.set_times(path, &metadata)?;
+    rewritten.push(path.to_owned());
+    Ok(())
+}
+
+/// Whether `target`, a path below `old_root`, was moved to `root` along with the entries that
+/// `selection` takes.
+fn was_moved(
+    fs: &dyn Filesystem,
+    root: &Path,
+    old_root: &Path,
+    target: &Path,
+    selection: &Selection,
+) -> bool {
+    let relative = target.strip_prefix(old_root).unwrap();
+    let moved_to = root.join(relative);
+    let file_type = [&moved_to, target]
+        .into_iter()
+        .find_map(|path| fs.symlink_metadata(path).ok())
+        .map_or(FileType::File, |metadata| metadata.file_type);
+    match selection.visit_path(relative, file_type) {
+        Visit::Take => true,
+        // A directory that only part of was moved exists in both places.
+        Visit::Descend => fs.symlink_metadata(&moved_to).is_ok(),
+        Visit::Skip => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs::{self, File};
+    use std::os::unix::fs::{symlink, FileTypeExt, MetadataExt, PermissionsExt};
+    use std::time::{Duration, SystemTime};
+
+    use tempfile::TempDir;
+
+    use super::*;
+    use crate::filesystem::{MemoryFs, RealFs};
+
+    /// The parts of `lstat` that a faithful copy must reproduce.
+    fn stat(path: &Path) -> (u32, u32, u32, i64, i64) {
+        let metadata = path.symlink_metadata().unwrap();
+        (
+            metadata.mode(),
+            metadata.uid(),
+            metadata.gid(),
+            metadata.mtime(),
+            metadata.mtime_nsec(),
+        )
+    }
+
+    fn set_mtime(path: &Path, seconds_ago: u64) {
+        let time = SystemTime::now() - Duration::from_secs(seconds_ago);
+        File::open(path).unwrap().set_modified(time).unwrap();
+    }
+
+    #[test]
+    fn test_copy_file_preserves_metadata() {
+        let temp_dir = TempDir::new().unwrap();
+        let from = temp_dir.path().join("id_rsa");
+        fs::write(&from, "secret").unwrap();
+        fs::set_permissions(&from, fs::Permissions::from_mode(0o600)).unwrap();
+        set_mtime(&from, 3600);
+        let to = temp_dir.path().join("id_rsa.bak");
+
+        copy_tree(&RealFs, &from, &to, &Selection::default()).unwrap();
+
+        assert_eq!(fs::read_to_string(&to).unwrap(), "secret");
+        assert_eq!(stat(&from), stat(&to));
+    }
+
+    #[test]
+    fn test_copy_tree_preserves_metadata() {
+        let temp_dir = TempDir::new().unwrap();
+        let from = temp_dir.path().join(".ssh");
+        fs::create_dir(&from).unwrap();
+        fs::write(from.join("config"), "Host *").unwrap();
+        fs::create_dir(from.join("keys")).unwrap();
+        fs::write(from.join("keys").join("key"), "key").unwrap();
+        symlink("config", from.join("link")).unwrap();
+        fs::set_permissions(from.join("keys"), fs::Permissions::from_mode(0o500)).unwrap();
+        fs::set_permissions(&from, fs::Permissions::from_mode(0o700)).unwrap();
+        set_mtime(&from.join("config"), 60);
+        set_mtime(&from, 7200);
+        let to = temp_dir.path().join(".ssh.bak");
+
+        copy_tree(&RealFs, &from, &to, &Selection::default()).unwrap();
+
+        for relative in ["", "config", "keys", "keys/key", "link"] {
+            assert_eq!(
+                stat(&from.join(relative)),
+                stat(&to.join(relative)),
+                "metadata of '{}' differs",
+                relative
+            );
+        }
+        assert_eq!(fs::read_link(to.join("link")).unwrap(), Path::new("config"));
+
+        // Let the temp dir clean up after itself.
+        fs::set_permissions(from.join("keys"), fs::Permissions::from_mode(0o700)).unwrap();
+        fs::set_permissions(to.join("keys"), fs::Permissions::from_mode(0o700)).unwrap();
+    }
+
+    #[test]
+    fn test_copy_preserves_xattrs() {
+        let temp_dir = TempDir::new().unwrap();
+        let from = temp_dir.path().join("file");
+        fs::write(&from, "content").unwrap();
+        if xattr::set(&from, "user.stowsave", b"value").is_err() {
+            println!("User xattrs are not supported here. Skipping test.");
+            return;
+        }
+        let to = temp_dir.path().join("copy");
+
+        copy_tree(&RealFs, &from, &to, &Selection::default()).unwrap();
+
+        assert_eq!(
+            xattr::get(&to, "user.stowsave").unwrap(),
+            Some(b"value".to_vec())
+        );
+    }
+
+    /// A directory containing a regular file, a FIFO and a socket.
+    fn create_dir_with_special_files(root: &Path) -> std::os::unix::net::UnixListener {
+        fs::create_dir(root).unwrap();
+        fs::write(root.join("file"), "content").unwrap();
+        RealFs.mkfifo(&root.join("fifo")).unwrap();
+        std::os::unix::net::UnixListener::bind(root.join("socket")).unwrap()
+    }
+
+    #[test]
+    fn test_find_special_files() {
+        let temp_dir = TempDir::new().unwrap();
+        let root = temp_dir.path().join("dir");
+        let _listener = create_dir_with_special_files(&root);
+
+        let mut found: Vec<_> = find_special_files(&RealFs, &root, &Selection::default())
+            .unwrap()
+            .iter()
+            .map(|special| (special.path.clone(), special.kind()))
+            .collect();
+        found.sort();
+        assert_eq!(
+            found,
+            vec![(root.join("fifo"), "FIFO"), (root.join("socket"), "socket")]
+        );
+    }
+
+    #[test]
+    fn test_copy_special_files() {
+        let temp_dir = TempDir::new().unwrap();
+        let from = temp_dir.path().join("dir");
+        let _listener = create_dir_with_special_files(&from);
+
+        let aborted = temp_dir.path().join("aborted");
+        assert!(copy_tree(&RealFs, &from, &aborted, &Selection::default()).is_err());
+
+        let excluded = temp_dir.path().join("excluded");
+        copy_tree(&RealFs, &from, &excluded, &SpecialFiles::Exclude.into()).unwrap();
+        assert!(excluded.join("file").is_file());
+        assert!(excluded.join("fifo").symlink_metadata().is_err());
+        assert!(excluded.join("socket").symlink_metadata().is_err());
+
+        let recreated = temp_dir.path().join("recreated");
+        copy_tree(&RealFs, &from, &recreated, &SpecialFiles::Recreate.into()).unwrap();
+        assert!(recreated.join("file").is_file());
+        let fifo_type = recreated
+            .join("fifo")
+            .symlink_metadata()
+            .unwrap()
+            .file_type();
+        assert!(fifo_type.is_fifo());
+        assert!(recreated.join("socket").symlink_metadata().is_err());
+    }
+
+    #[test]
+    fn test_copy_tree_preserves_symlinks() {
+        let temp_dir = TempDir::new().unwrap();
+        let from = temp_dir.path().join("foo");
+        fs::create_dir_all(from.join("themes")).unwrap();
+        fs::write(from.join("themes").join("dark"), "dark").unwrap();
+        symlink("themes", from.join("themes_link")).unwrap();
+        symlink("themes/dark", from.join("current")).unwrap();
+        symlink("does_not_exist", from.join("dangling")).unwrap();
+        symlink("/etc/hosts", from.join("outside")).unwrap();
+        let to = temp_dir.path().join("foo.bak");
+
+        copy_tree(&RealFs, &from, &to, &Selection::default()).unwrap();
+
+        for (link, target) in [
+            ("themes_link", "themes"),
+            ("current", "themes/dark"),
+            ("dangling", "does_not_exist"),
+            ("outside", "/etc/hosts"),
+        ] {
+            assert!(to.join(link).is_symlink(), "'{}' is not a symlink", link);
+            assert_eq!(fs::read_link(to.join(link)).unwrap(), Path::new(target));
+        }
+        // The linked directory wasn't copied through the link.
+        assert_eq!(fs::read_dir(&to).unwrap().count(), 5);
+    }
+
+    #[test]
+    fn test_relativize_links() {
+        let temp_dir = TempDir::new().unwrap();
+        let old_root = temp_dir.path().join("old").join("foo");
+        let root = temp_dir.path().join("new").join("foo");
+        fs::create_dir_all(root.join("sub")).unwrap();
+        fs::write(root.join("config"), "config").unwrap();
+        symlink(old_root.join("config"), root.join("sub").join("inner")).unwrap();
+        symlink(&old_root, root.join("self")).unwrap();
+        symlink("/etc/hosts", root.join("outside")).unwrap();
+        symlink("config", root.join("relative")).unwrap();
+
+        let mut rewritten =
+            relativize_links(&RealFs, &root, &old_root, &Selection::default()).unwrap();
+        rewritten.sort();
+
+        assert_eq!(rewritten, vec![root.join("self"), root.join("sub/inner")]);
+        assert_eq!(
+            fs::read_link(root.join("sub/inner")).unwrap(),
+            Path::new("../config")
+        );
+        assert_eq!(
+            fs::read_to_string(root.join("sub/inner")).unwrap(),
+            "config"
+        );
+        assert_eq!(fs::read_link(root.join("self")).unwrap(), Path::new("."));
+        assert_eq!(
+            fs::read_link(root.join("outside")).unwrap(),
+            Path::new("/etc/hosts")
+        );
+        assert_eq!(
+            fs::read_link(root.join("relative")).unwrap(),
+            Path::new("config")
+        );
+    }
+
+    #[test]
+    fn test_copy_tree_preserves_ownership_as_root() {
+        let fs = MemoryFs::new();
+        fs.set_root(true);
+        let from = Path::new("/etc/app");
+        fs.create_dir_all(from).unwrap();
+        fs.write(&from.join("config"), "secret").unwrap();
+        fs.set_owner(&from.join("config"), 0, 42).unwrap();
+        fs.set_mode(&from.join("config"), 0o640).unwrap();
+        fs.set_owner(from, 0, 0).unwrap();
+        let to = Path::new("/etc/app.bak");
+
+        copy_tree(&fs, from, to, &Selection::default()).unwrap();
+
+        for relative in ["", "config"] {
+            let (original, copy) = (from.join(relative), to.join(relative));
+            let original = fs.symlink_metadata(&original).unwrap();
+            let copy = fs.symlink_metadata(&copy).unwrap();
+            assert_eq!(
+                (original.uid, original.gid, original.mode, original.mtime),
+                (copy.uid, copy.gid, copy.mode, copy.mtime),
+                "metadata of '{}' differs",
+                relative
+            );
+        }
+    }
+}
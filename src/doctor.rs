@@ -0,0 +1,96 @@
+//! Checking that stowsave can do its job on this machine, for `stowsave doctor`.
+use std::path::Path;
+
+use crate::checks;
+use crate::config::Config;
+use crate::error::Error;
+use crate::filesystem::Filesystem;
+use crate::stow;
+
+/// The outcome of one check: what was found, or what is wrong.
+#[derive(Debug)]
+pub struct Check {
+    /// What was checked, like `stow`.
+    pub name: &'static str,
+    pub result: Result<String, Error>,
+}
+
+/// Check that the config file at `config_path`, or the default one, is valid, that `stow_bin`
+/// runs and is recent enough, and that the stow directory set in `~/.stowrc` or `$STOW_DIR`
+/// exists.
+pub fn diagnose(fs: &dyn Filesystem, config_path: Option<&Path>, stow_bin: &Path) -> Vec<Check> {
+    vec![
+        Check {
+            name: "config",
+            result: Config::load(config_path)
+                .map(|_| "The config file is valid, or there is none".to_string())
+                .map_err(Error::from),
+        },
+        Check {
+            name: "stow",
+            result: check_stow(fs, stow_bin),
+        },
+        Check {
+            name: "stow directory",
+            result: check_stow_dir(fs).map_err(Error::from),
+        },
+    ]
+}
+
+fn check_stow(fs: &dyn Filesystem, stow_bin: &Path) -> Result<String, Error> {
+    let version = stow::detect_version(fs, stow_bin).map_err(anyhow::Error::from)?;
+    Ok(if version.supports_dotfiles() {
+        format!("Found stow {}", version)
+    } else {
+        format!("Found stow {}, which is too old for --dotfiles", version)
+    })
+}
+
+fn check_stow_dir(fs: &dyn Filesystem) -> anyhow::Result<String> {
+    let home_rc = stow::read_home_stowrc(fs)?;
+    match stow::default_stow_dir(fs, &home_rc) {
+        Some(stow_dir) => {
+            checks::stow_directory_exists(fs, &stow_dir)?;
+            Ok(format!("Stow directory '{}'", stow_dir.display()))
+        }
+        None => Ok(
+            "No stow directory is set in ~/.stowrc or $STOW_DIR, so packages have to be given as \
+             paths"
+                .to_string(),
+        ),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::filesystem::MemoryFs;
+
+    #[test]
+    fn test_diagnose() {
+        let fs = MemoryFs::new();
+        fs.set_var("STOW_DIR", "/home/user/dotfiles");
+        fs.set_stow_version("2.2.2");
+
+        let checks = diagnose(&fs, Some(Path::new("/nonexistent")), Path::new("stow"));
+        let results: Vec<(&str, Result<&str, &str>)> = checks
+            .iter()
+            .map(|check| (check.name, check.result.as_deref().map_err(Error::code)))
+            .collect();
+        assert_eq!(
+            results,
+            vec![
+                ("config", Err("io_error")),
+                (
+                    "stow",
+                    Ok("Found stow 2.2.2, which is too old for --dotfiles")
+                ),
+                ("stow directory", Err("invalid_stow_directory")),
+            ]
+        );
+
+        fs.create_dir_all(Path::new("/home/user/dotfiles")).unwrap();
+        let checks = diagnose(&fs, None, Path::new("stow"));
+        assert!(checks[2].result.is_ok());
+    }
+}
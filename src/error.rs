@@ -0,0 +1,232 @@
+//! The error type of the library.
+//!
+//! Planning and executing report failures as an [`Error`](enum@Error), whose variant says what kind of
+//! failure it was and whether anything was changed. The command line tool exits with
+//! [`Error::exit_code`]:
+//!
+//! | Code | Variant | Meaning |
+//! |------|---------|---------|
+//! | 0 | | Success |
+//! | 1 | [`Error::Other`] | Any other error. Nothing was changed |
+//! | 2 | | Invalid command line. Nothing was changed |
+//! | 3 | [`Error::Validation`] | The path can't be saved as asked. Nothing was changed |
+//! | 4 | [`Error::Conflict`] | Something is in the way in the package, or where the backup goes. Nothing was changed |
+//! | 5 | [`Error::Io`] | Reading the filesystem failed. Nothing was changed |
+//! | 6 | [`Error::Stow`] | Stow can't be run or is too old. Nothing was changed |
+//! | 7 | [`Error::RolledBack`] | A step failed and the steps before it were undone |
+//! | 8 | [`Error::PartiallyApplied`] | A step failed and it, or the steps before it, couldn't be undone |
+//! | 9 | [`Error::Locked`] | Another run holds the lock on the stow directory. Nothing was changed |
+use std::fmt;
+use std::io;
+use std::path::PathBuf;
+
+use thiserror::Error;
+
+use crate::checks::StowSaveError;
+use crate::stow::StowError;
+
+#[derive(Error, Debug)]
+#[non_exhaustive]
+pub enum Error {
+    /// The path can't be saved as asked, for example because it is on the deny-list.
+    #[error(transparent)]
+    Validation(anyhow::Error),
+    /// Something already exists where the save would put the path or its backup.
+    #[error(transparent)]
+    Conflict(anyhow::Error),
+    /// Reading the filesystem failed while planning.
+    #[error(transparent)]
+    Io(anyhow::Error),
+    /// Stow can't be run, or its version can't be used.
+    #[error(transparent)]
+    Stow(anyhow::Error),
+    /// A step of the plan failed with `cause`, and the steps before it were undone.
+    #[error("{cause:#}\nThe steps taken before were undone, so nothing was changed")]
+    RolledBack { cause: anyhow::Error },
+    /// A step of the plan failed with `cause`, and the steps before it couldn't all be undone:
+    /// undoing one failed with `undo_error`, or, if there is none, the failed step may have been
+    /// partly carried out, so nothing was undone. `applied` describes the steps that are still
+    /// in effect, and `journal` is the file they were written to, if writing it worked.
+    #[error(
+        "{cause:#}\n{}These steps are still in effect:{}{}",
+        describe_undo_error(.undo_error.as_ref()),
+        format_steps(.applied),
+        .journal
+            .as_ref()
+            .map(|journal| format!("\nThey are recorded in '{}'", journal.display()))
+            .unwrap_or_default()
+    )]
+    PartiallyApplied {
+        cause: anyhow::Error,
+        undo_error: Option<anyhow::Error>,
+        applied: Vec<String>,
+        journal: Option<PathBuf>,
+    },
+    /// Another process holds the [lock](crate::lock) on the stow directory.
+    #[error(
+        "Stow directory '{}' is in use by another stowsave{}. Use --wait to wait for it to finish",
+        .stow_dir.display(),
+        .pid.map(|pid| format!(" (PID {})", pid)).unwrap_or_default()
+    )]
+    Locked {
+        stow_dir: PathBuf,
+        /// The process holding the lock, if it could be told.
+        pid: Option<u32>,
+    },
+    #[error(transparent)]
+    Other(anyhow::Error),
+}
+
+impl Error {
+    /// The exit status of the command line tool for this error. See the [module docs](self).
+    pub fn exit_code(&self) -> u8 {
+        match self {
+            Error::Other(_) => 1,
+            Error::Validation(_) => 3,
+            Error::Conflict(_) => 4,
+            Error::Io(_) => 5,
+            Error::Stow(_) => 6,
+            Error::RolledBack { .. } => 7,
+            Error::PartiallyApplied { .. } => 8,
+            Error::Locked { .. } => 9,
+        }
+    }
+
+    /// A stable identifier of what went wrong, such as `path_is_denied` or `stow_conflicts`.
+    pub fn code(&self) -> &'static str {
+        match (self, self.cause()) {
+            (Error::Locked { .. }, _) => "stow_dir_locked",
+            (_, Some(cause)) => error_code(cause),
+            (_, None) => "other",
+        }
+    }
+
+    /// The error behind this one, of type `E`, such as a [`StowSaveError`].
+    pub fn downcast_ref<E>(&self) -> Option<&E>
+    where
+        E: fmt::Display + fmt::Debug + Send + Sync + 'static,
+    {
+        self.cause()?.downcast_ref()
+    }
+
+    fn cause(&self) -> Option<&anyhow::Error> {
+        match self {
+            Error::Validation(cause)
+            | Error::Conflict(cause)
+            | Error::Io(cause)
+            | Error::Stow(cause)
+            | Error::Other(cause)
+            | Error::RolledBack { cause }
+            | Error::PartiallyApplied { cause, .. } => Some(cause),
+            Error::Locked { .. } => None,
+        }
+    }
+}
+
+/// Sort an error raised while planning into its category.
+impl From<anyhow::Error> for Error {
+    fn from(err: anyhow::Error) -> Error {
+        let err = match err.downcast::<Error>() {
+            Ok(err) => return err,
+            Err(err) => err,
+        };
+        for cause in err.chain() {
+            if let Some(check) = cause.downcast_ref::<StowSaveError>() {
+                return match check {
+                    StowSaveError::PathAlreadyExists(_)
+                    | StowSaveError::BackupAlreadyExists(_)
+                    | StowSaveError::AlreadyInPackage(..) => Error::Conflict(err),
+                    _ => Error::Validation(err),
+                };
+            }
+            if let Some(stow) = cause.downcast_ref::<StowError>() {
+                return match stow {
+                    StowError::Conflicts { .. } => Error::Conflict(err),
+                    _ => Error::Stow(err),
+                };
+            }
+        }
+        if err.chain().any(|cause| cause.is::<io::Error>()) {
+            Error::Io(err)
+        } else {
+            Error::Other(err)
+        }
+    }
+}
+
+/// The stable code of an error raised while planning or executing.
+pub fn error_code(err: &anyhow::Error) -> &'static str {
+    for cause in err.chain() {
+        if let Some(err) = cause.downcast_ref::<Error>() {
+            return err.code();
+        }
+        if let Some(err) = cause.downcast_ref::<StowSaveError>() {
+            return err.code();
+        }
+        if let Some(err) = cause.downcast_ref::<StowError>() {
+            return err.code();
+        }
+    }
+    if err.chain().any(|cause| cause.is::<io::Error>()) {
+        "io_error"
+    } else {
+        "other"
+    }
+}
+
+fn describe_undo_error(undo_error: Option<&anyhow::Error>) -> String {
+    match undo_error {
+        Some(undo_error) => format!("Undoing the steps taken before failed: {:#}\n", undo_error),
+        None => "The failed step may have been partly carried out, so nothing was undone\n".into(),
+    }
+}
+
+fn format_steps(steps: &[String]) -> String {
+    steps.iter().map(|step| format!("\n  {}", step)).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_errors_are_categorized() {
+        let denied: Error =
+            anyhow::Error::from(StowSaveError::PathIsDenied("a".into(), "b".into())).into();
+        assert!(matches!(denied, Error::Validation(_)));
+        assert_eq!(denied.exit_code(), 3);
+        assert_eq!(denied.code(), "path_is_denied");
+        assert!(denied.downcast_ref::<StowSaveError>().is_some());
+
+        let exists: Error = anyhow::Error::from(StowSaveError::PathAlreadyExists("a".into()))
+            .context("Failed to plan")
+            .into();
+        assert!(matches!(exists, Error::Conflict(_)));
+
+        let io: Error = anyhow::Error::from(io::Error::other("disk full"))
+            .context("Failed to scan")
+            .into();
+        assert!(matches!(io, Error::Io(_)));
+        assert_eq!(io.code(), "io_error");
+
+        assert!(matches!(
+            Error::from(anyhow::anyhow!("bad")),
+            Error::Other(_)
+        ));
+    }
+
+    #[test]
+    fn test_error_survives_anyhow() {
+        let err = Error::RolledBack {
+            cause: StowError::MissingPackage {
+                package: "vim".into(),
+            }
+            .into(),
+        };
+        let err: Error = anyhow::Error::from(err).into();
+
+        assert_eq!(err.exit_code(), 7);
+        assert_eq!(err.code(), "stow_missing_package");
+        assert!(err.to_string().ends_with("nothing was changed"));
+    }
+}
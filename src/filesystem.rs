@@ -0,0 +1,1335 @@
+//! The filesystem operations that planning and executing a save rely on.
+//!
+//! Everything stowsave does to files goes through a [`Filesystem`], including running stow.
+//! [`RealFs`] is the filesystem of the machine. [`MemoryFs`] keeps a tree of files, directories,
+//! symlinks and FIFOs in memory, with modes, ownership, timestamps and extended attributes, so
+//! that plans can be built and executed against it without touching the disk.
+use std::cell::RefCell;
+use std::collections::{BTreeMap, VecDeque};
+use std::ffi::{CString, OsStr, OsString};
+use std::fs;
+use std::io::{self, Read};
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::{lchown, symlink, FileTypeExt, MetadataExt, PermissionsExt};
+use std::os::unix::process::ExitStatusExt;
+use std::path::{Component, Path, PathBuf};
+use std::process::{Command as ProcessCommand, ExitStatus, Output};
+
+/// The type of a filesystem entry.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FileType {
+    File,
+    Dir,
+    Symlink,
+    Fifo,
+    Socket,
+    CharDevice,
+    BlockDevice,
+}
+
+impl FileType {
+    pub fn is_file(self) -> bool {
+        self == FileType::File
+    }
+
+    pub fn is_dir(self) -> bool {
+        self == FileType::Dir
+    }
+
+    pub fn is_symlink(self) -> bool {
+        self == FileType::Symlink
+    }
+
+    pub fn is_fifo(self) -> bool {
+        self == FileType::Fifo
+    }
+
+    /// Sockets, FIFOs and device nodes.
+    pub fn is_special(self) -> bool {
+        !self.is_file() && !self.is_dir() && !self.is_symlink()
+    }
+}
+
+impl From<fs::FileType> for FileType {
+    fn from(file_type: fs::FileType) -> FileType {
+        if file_type.is_dir() {
+            FileType::Dir
+        } else if file_type.is_symlink() {
+            FileType::Symlink
+        } else if file_type.is_fifo() {
+            FileType::Fifo
+        } else if file_type.is_socket() {
+            FileType::Socket
+        } else if file_type.is_char_device() {
+            FileType::CharDevice
+        } else if file_type.is_block_device() {
+            FileType::BlockDevice
+        } else {
+            FileType::File
+        }
+    }
+}
+
+/// The parts of `stat` that stowsave looks at.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Metadata {
+    pub file_type: FileType,
+    /// The permission bits, including the setuid, setgid and sticky bits.
+    pub mode: u32,
+    pub uid: u32,
+    pub gid: u32,
+    pub dev: u64,
+    pub ino: u64,
+    pub nlink: u64,
+    pub len: u64,
+    pub atime: i64,
+    pub atime_nsec: i64,
+    pub mtime: i64,
+    pub mtime_nsec: i64,
+}
+
+impl Metadata {
+    pub fn is_file(&self) -> bool {
+        self.file_type.is_file()
+    }
+
+    pub fn is_dir(&self) -> bool {
+        self.file_type.is_dir()
+    }
+
+    pub fn is_symlink(&self) -> bool {
+        self.file_type.is_symlink()
+    }
+}
+
+impl From<fs::Metadata> for Metadata {
+    fn from(metadata: fs::Metadata) -> Metadata {
+        Metadata {
+            file_type: metadata.file_type().into(),
+            mode: metadata.mode() & 0o7777,
+            uid: metadata.uid(),
+            gid: metadata.gid(),
+            dev: metadata.dev(),
+            ino: metadata.ino(),
+            nlink: metadata.nlink(),
+            len: metadata.len(),
+            atime: metadata.atime(),
+            atime_nsec: metadata.atime_nsec(),
+            mtime: metadata.mtime(),
+            mtime_nsec: metadata.mtime_nsec(),
+        }
+    }
+}
+
+/// The operations stowsave performs on files, and running programs such as stow.
+///
+/// Methods behave like their namesakes in [`std::fs`]: `symlink_metadata`, `read_link`,
+/// `set_owner`, `set_times` and the extended attribute methods act on a symlink itself, the
+/// others follow it.
+pub trait Filesystem {
+    fn metadata(&self, path: &Path) -> io::Result<Metadata>;
+    fn symlink_metadata(&self, path: &Path) -> io::Result<Metadata>;
+    /// The paths of the entries of the directory at `path`.
+    fn read_dir(&self, path: &Path) -> io::Result<Vec<PathBuf>>;
+    fn read_link(&self, path: &Path) -> io::Result<PathBuf>;
+    fn canonicalize(&self, path: &Path) -> io::Result<PathBuf>;
+    fn open(&self, path: &Path) -> io::Result<Box<dyn Read + '_>>;
+    fn create_dir(&self, path: &Path) -> io::Result<()>;
+    fn remove_file(&self, path: &Path) -> io::Result<()>;
+    fn remove_dir(&self, path: &Path) -> io::Result<()>;
+    fn rename(&self, from: &Path, to: &Path) -> io::Result<()>;
+    fn symlink(&self, target: &Path, path: &Path) -> io::Result<()>;
+    /// Copy the contents and permission bits of a file.
+    fn copy_file(&self, from: &Path, to: &Path) -> io::Result<()>;
+    /// Create or replace the file at `path` with `contents`.
+    fn write_file(&self, path: &Path, contents: &[u8]) -> io::Result<()>;
+    fn mkfifo(&self, path: &Path) -> io::Result<()>;
+    fn set_mode(&self, path: &Path, mode: u32) -> io::Result<()>;
+    fn set_owner(&self, path: &Path, uid: u32, gid: u32) -> io::Result<()>;
+    /// Set the access and modification times to those in `times`.
+    fn set_times(&self, path: &Path, times: &Metadata) -> io::Result<()>;
+    fn list_xattrs(&self, path: &Path) -> io::Result<Vec<OsString>>;
+    fn get_xattr(&self, path: &Path, name: &OsStr) -> io::Result<Option<Vec<u8>>>;
+    fn set_xattr(&self, path: &Path, name: &OsStr, value: &[u8]) -> io::Result<()>;
+    /// Whether we may give files away to other users.
+    fn is_root(&self) -> bool;
+    /// The value of the environment variable `name`, as seen by the programs that are run.
+    fn var(&self, name: &str) -> Option<OsString>;
+    /// Run `program` with `args`, in `dir` if given, and collect its output.
+    fn run(&self, program: &Path, args: &[OsString], dir: Option<&Path>) -> io::Result<Output>;
+
+    /// Whether `path` exists, following symlinks.
+    fn exists(&self, path: &Path) -> bool {
+        self.metadata(path).is_ok()
+    }
+
+    fn is_file(&self, path: &Path) -> bool {
+        self.metadata(path).is_ok_and(|metadata| metadata.is_file())
+    }
+
+    fn is_dir(&self, path: &Path) -> bool {
+        self.metadata(path).is_ok_and(|metadata| metadata.is_dir())
+    }
+
+    fn is_symlink(&self, path: &Path) -> bool {
+        self.symlink_metadata(path)
+            .is_ok_and(|metadata| metadata.is_symlink())
+    }
+
+    fn read(&self, path: &Path) -> io::Result<Vec<u8>> {
+        let mut contents = Vec::new();
+        self.open(path)?.read_to_end(&mut contents)?;
+        Ok(contents)
+    }
+
+    fn read_to_string(&self, path: &Path) -> io::Result<String> {
+        String::from_utf8(self.read(path)?)
+            .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))
+    }
+
+    fn create_dir_all(&self, path: &Path) -> io::Result<()> {
+        if self.is_dir(path) {
+            return Ok(());
+        }
+        if let Some(parent) = path
+            .parent()
+            .filter(|parent| !parent.as_os_str().is_empty())
+        {
+            self.create_dir_all(parent)?;
+        }
+        match self.create_dir(path) {
+            Err(_) if self.is_dir(path) => Ok(()),
+            result => result,
+        }
+    }
+
+    fn remove_dir_all(&self, path: &Path) -> io::Result<()> {
+        for entry in self.read_dir(path)? {
+            if self.symlink_metadata(&entry)?.is_dir() {
+                self.remove_dir_all(&entry)?;
+            } else {
+                self.remove_file(&entry)?;
+            }
+        }
+        self.remove_dir(path)
+    }
+}
+
+/// The filesystem of the machine stowsave runs on.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct RealFs;
+
+impl Filesystem for RealFs {
+    fn metadata(&self, path: &Path) -> io::Result<Metadata> {
+        fs::metadata(path).map(Metadata::from)
+    }
+
+    fn symlink_metadata(&self, path: &Path) -> io::Result<Metadata> {
+        fs::symlink_metadata(path).map(Metadata::from)
+    }
+
+    fn read_dir(&self, path: &Path) -> io::Result<Vec<PathBuf>> {
+        fs::read_dir(path)?
+            .map(|entry| entry.map(|entry| entry.path()))
+            .collect()
+    }
+
+    fn read_link(&self, path: &Path) -> io::Result<PathBuf> {
+        fs::read_link(path)
+    }
+
+    fn canonicalize(&self, path: &Path) -> io::Result<PathBuf> {
+        fs::canonicalize(path)
+    }
+
+    fn open(&self, path: &Path) -> io::Result<Box<dyn Read + '_>> {
+        Ok(Box::new(fs::File::open(path)?))
+    }
+
+    fn create_dir(&self, path: &Path) -> io::Result<()> {
+        fs::create_dir(path)
+    }
+
+    fn remove_file(&self, path: &Path) -> io::Result<()> {
+        fs::remove_file(path)
+    }
+
+    fn remove_dir(&self, path: &Path) -> io::Result<()> {
+        fs::remove_dir(path)
+    }
+
+    fn rename(&self, from: &Path, to: &Path) -> io::Result<()> {
+        fs::rename(from, to)
+    }
+
+    fn symlink(&self, target: &Path, path: &Path) -> io::Result<()> {
+        symlink(target, path)
+    }
+
+    fn copy_file(&self, from: &Path, to: &Path) -> io::Result<()> {
+        fs::copy(from, to).map(|_| ())
+    }
+
+    fn write_file(&self, path: &Path, contents: &[u8]) -> io::Result<()> {
+        fs::write(path, contents)
+    }
+
+    fn mkfifo(&self, path: &Path) -> io::Result<()> {
+        let path = CString::new(path.as_os_str().as_bytes())?;
+        // SAFETY: `path` is a valid NUL-terminated string.
+        if unsafe { libc::mkfifo(path.as_ptr(), 0o600) } == 0 {
+            Ok(())
+        } else {
+            Err(io::Error::last_os_error())
+        }
+    }
+
+    fn set_mode(&self, path: &Path, mode: u32) -> io::Result<()> {
+        fs::set_permissions(path, fs::Permissions::from_mode(mode))
+    }
+
+    fn set_owner(&self, path: &Path, uid: u32, gid: u32) -> io::Result<()> {
+        lchown(path, Some(uid), Some(gid))
+    }
+
+    fn set_times(&self, path: &Path, times: &Metadata) -> io::Result<()> {
+        let path = CString::new(path.as_os_str().as_bytes())?;
+        let times = [
+            libc::timespec {
+                tv_sec: times.atime,
+                tv_nsec: times.atime_nsec,
+            },
+            libc::timespec {
+                tv_sec: times.mtime,
+                tv_nsec: times.mtime_nsec,
+            },
+        ];
+        // SAFETY: `path` is a valid NUL-terminated string and `times` holds exactly two
+        // timespecs.
+        let result = unsafe {
+            libc::utimensat(
+                libc::AT_FDCWD,
+                path.as_ptr(),
+                times.as_ptr(),
+                libc::AT_SYMLINK_NOFOLLOW,
+            )
+        };
+        if result == 0 {
+            Ok(())
+        } else {
+            Err(io::Error::last_os_error())
+        }
+    }
+
+    fn list_xattrs(&self, path: &Path) -> io::Result<Vec<OsString>> {
+        Ok(xattr::list(path)?.collect())
+    }
+
+    fn get_xattr(&self, path: &Path, name: &OsStr) -> io::Result<Option<Vec<u8>>> {
+        xattr::get(path, name)
+    }
+
+    fn set_xattr(&self, path: &Path, name: &OsStr, value: &[u8]) -> io::Result<()> {
+        xattr::set(path, name, value)
+    }
+
+    fn is_root(&self) -> bool {
+        // SAFETY: geteuid has no preconditions and cannot fail.
+        unsafe { libc::geteuid() == 0 }
+    }
+
+    fn var(&self, name: &str) -> Option<OsString> {
+        std::env::var_os(name)
+    }
+
+    fn run(&self, program: &Path, args: &[OsString], dir: Option<&Path>) -> io::Result<Output> {
+        let mut command = ProcessCommand::new(program);
+        command.args(args);
+        if let Some(dir) = dir {
+            command.current_dir(dir);
+        }
+        command.output()
+    }
+
+    fn create_dir_all(&self, path: &Path) -> io::Result<()> {
+        fs::create_dir_all(path)
+    }
+
+    fn remove_dir_all(&self, path: &Path) -> io::Result<()> {
+        fs::remove_dir_all(path)
+    }
+}
+
+/// Copies entries from another filesystem into the state of a [`MemoryFs`], for
+/// [`MemoryFs::snapshot`].
+struct Snapshot<'a> {
+    fs: &'a dyn Filesystem,
+    state: &'a mut State,
+    /// The inodes copied so far, by the device and inode number they have on `fs`.
+    copied: BTreeMap<(u64, u64), u64>,
+    /// The paths copied with everything below them.
+    trees: Vec<PathBuf>,
+}
+
+impl Snapshot<'_> {
+    /// Copy the entry at `path`, which doesn't have to exist, and the directories above it. If
+    /// `recursive` is set, everything below it is copied too.
+    fn add(&mut self, path: &Path, recursive: bool) -> io::Result<()> {
+        if self.trees.iter().any(|tree| path.starts_with(tree)) {
+            return Ok(());
+        }
+        let mut ancestors: Vec<&Path> = path.ancestors().skip(1).collect();
+        ancestors.reverse();
+        for ancestor in ancestors {
+            let metadata = match self.fs.symlink_metadata(ancestor) {
+                Ok(metadata) => metadata,
+                Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(()),
+                Err(err) => return Err(err),
+            };
+            self.copy_entry(ancestor, &metadata)?;
+            if metadata.is_symlink() {
+                // What is below the link lives below its target.
+                let Ok(target) = self.fs.canonicalize(ancestor) else {
+                    return Ok(());
+                };
+                return self.add(
+                    &target.join(path.strip_prefix(ancestor).unwrap()),
+                    recursive,
+                );
+            }
+        }
+        if recursive {
+            self.trees.push(path.to_owned());
+        }
+        let metadata = match self.fs.symlink_metadata(path) {
+            Ok(metadata) => metadata,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(()),
+            Err(err) => return Err(err),
+        };
+        if recursive {
+            self.copy_tree(path, &metadata)?;
+        } else {
+            self.copy_entry(path, &metadata)?;
+        }
+        if metadata.is_symlink() {
+            if let Ok(target) = self.fs.canonicalize(path) {
+                self.add(&target, recursive)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn copy_tree(&mut self, path: &Path, metadata: &Metadata) -> io::Result<()> {
+        self.copy_entry(path, metadata)?;
+        if metadata.is_dir() {
+            for entry in self.fs.read_dir(path)? {
+                let metadata = self.fs.symlink_metadata(&entry)?;
+                self.copy_tree(&entry, &metadata)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Copy the entry at `path`, without what is inside it, unless it was copied before.
+    fn copy_entry(&mut self, path: &Path, metadata: &Metadata) -> io::Result<()> {
+        if self.state.entries.contains_key(path) {
+            return Ok(());
+        }
+        let key = (metadata.dev, metadata.ino);
+        if let Some(&ino) = self.copied.get(&key) {
+            self.state.entries.insert(path.to_owned(), ino);
+            self.state.inodes.get_mut(&ino).unwrap().nlink += 1;
+            return Ok(());
+        }
+        let data = match metadata.file_type {
+            FileType::File => match self.fs.read(path) {
+                Ok(contents) => Data::File(contents),
+                // The copy keeps the mode, so reading it fails just the same.
+                Err(err) if err.kind() == io::ErrorKind::PermissionDenied => Data::File(Vec::new()),
+                Err(err) => return Err(err),
+            },
+            FileType::Dir => Data::Dir,
+            FileType::Symlink => Data::Symlink(self.fs.read_link(path)?),
+            file_type => Data::Special(file_type),
+        };
+        let ino = self.state.add_inode(data, metadata.mode);
+        self.state.copy_metadata(ino, metadata);
+        let inode = self.state.inodes.get_mut(&ino).unwrap();
+        inode.nlink = 1;
+        for name in self.fs.list_xattrs(path).unwrap_or_default() {
+            if let Some(value) = self.fs.get_xattr(path, &name)? {
+                inode.xattrs.insert(name, value);
+            }
+        }
+        self.state.entries.insert(path.to_owned(), ino);
+        self.copied.insert(key, ino);
+        if self.state.dev(path) != metadata.dev {
+            self.state.mounts.insert(path.to_owned(), metadata.dev);
+        }
+        Ok(())
+    }
+}
+
+/// A program run through a [`MemoryFs`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Run {
+    pub program: PathBuf,
+    pub args: Vec<OsString>,
+    pub dir: Option<PathBuf>,
+}
+
+/// A filesystem that only exists in memory.
+///
+/// It starts out as an empty root directory owned by the current user. Paths must be absolute;
+/// relative ones are taken relative to `/`. Permission bits are enforced for the owner, unless
+/// the filesystem is [`MemoryFs::set_root`]. Every change advances a clock that stands in for
+/// the time, so timestamps are deterministic.
+///
+/// Programs aren't run. [`Filesystem::run`] records them, answers `--version` with the stow
+/// version given to [`MemoryFs::set_stow_version`] and otherwise succeeds without output. The
+/// environment is empty until [`MemoryFs::set_var`] fills it in.
+#[derive(Debug)]
+pub struct MemoryFs {
+    state: RefCell<State>,
+}
+
+#[derive(Debug)]
+struct State {
+    /// Every path, mapped to its inode.
+    entries: BTreeMap<PathBuf, u64>,
+    inodes: BTreeMap<u64, Inode>,
+    next_ino: u64,
+    clock: i64,
+    /// Directories where a different device is mounted.
+    mounts: BTreeMap<PathBuf, u64>,
+    uid: u32,
+    gid: u32,
+    root: bool,
+    stow_version: String,
+    runs: Vec<Run>,
+    vars: BTreeMap<String, OsString>,
+}
+
+#[derive(Debug, Clone)]
+struct Inode {
+    data: Data,
+    mode: u32,
+    uid: u32,
+    gid: u32,
+    nlink: u64,
+    atime: i64,
+    atime_nsec: i64,
+    mtime: i64,
+    mtime_nsec: i64,
+    xattrs: BTreeMap<OsString, Vec<u8>>,
+}
+
+#[derive(Debug, Clone)]
+enum Data {
+    File(Vec<u8>),
+    Dir,
+    Symlink(PathBuf),
+    /// A FIFO, socket or device node.
+    Special(FileType),
+}
+
+/// How many symlinks a path may pass through, as on Linux.
+const MAX_SYMLINKS: usize = 40;
+
+fn error(code: i32) -> io::Error {
+    io::Error::from_raw_os_error(code)
+}
+
+impl Default for MemoryFs {
+    fn default() -> MemoryFs {
+        MemoryFs::new()
+    }
+}
+
+impl MemoryFs {
+    /// An empty filesystem owned by uid and gid 1000.
+    pub fn new() -> MemoryFs {
+        let mut state = State {
+            entries: BTreeMap::new(),
+            inodes: BTreeMap::new(),
+            next_ino: 1,
+            clock: 0,
+            mounts: BTreeMap::new(),
+            uid: 1000,
+            gid: 1000,
+            root: false,
+            stow_version: "2.3.1".to_string(),
+            runs: Vec::new(),
+            vars: BTreeMap::new(),
+        };
+        let ino = state.add_inode(Data::Dir, 0o755);
+        state.entries.insert(PathBuf::from("/"), ino);
+        MemoryFs {
+            state: RefCell::new(state),
+        }
+    }
+
+    /// Act as root, which may change ownership and ignores permission bits.
+    pub fn set_root(&self, root: bool) {
+        self.state.borrow_mut().root = root;
+    }
+
+    /// The version `stow --version` reports.
+    pub fn set_stow_version(&self, version: &str) {
+        self.state.borrow_mut().stow_version = version.to_string();
+    }
+
+    /// Set the environment variable `name`.
+    pub fn set_var(&self, name: &str, value: impl AsRef<OsStr>) {
+        self.state
+            .borrow_mut()
+            .vars
+            .insert(name.to_string(), value.as_ref().to_owned());
+    }
+
+    /// Put what is at and below `dir` on a device of its own, so that renames out of it fail
+    /// like they do across filesystems.
+    pub fn mount(&self, dir: &Path) {
+        let mut state = self.state.borrow_mut();
+        let dev = state.mounts.len() as u64 + 2;
+        state.mounts.insert(dir.to_owned(), dev);
+    }
+
+    /// Create or replace the file at `path`, with mode 0644.
+    pub fn write(&self, path: &Path, contents: impl AsRef<[u8]>) -> io::Result<()> {
+        let mut state = self.state.borrow_mut();
+        let path = state.resolve(path, true)?;
+        match state.entries.get(&path).copied() {
+            Some(ino) => {
+                state.check_access(&path, 0o200)?;
+                let clock = state.tick();
+                let inode = state.inodes.get_mut(&ino).unwrap();
+                match &mut inode.data {
+                    Data::File(data) => *data = contents.as_ref().to_vec(),
+                    _ => return Err(error(libc::EISDIR)),
+                }
+                inode.mtime = clock;
+                Ok(())
+            }
+            None => state
+                .create(&path, Data::File(contents.as_ref().to_vec()), 0o644)
+                .map(|_| ()),
+        }
+    }
+
+    /// Give `path` another name, `link`.
+    pub fn hard_link(&self, path: &Path, link: &Path) -> io::Result<()> {
+        let mut state = self.state.borrow_mut();
+        let path = state.resolve(path, false)?;
+        let link = state.resolve(link, false)?;
+        let ino = state.lookup(&path)?;
+        if state.inodes[&ino].data.is_dir() {
+            return Err(error(libc::EPERM));
+        }
+        if state.dev(&path) != state.dev(&link) {
+            return Err(error(libc::EXDEV));
+        }
+        state.link(&link, ino)
+    }
+
+    /// The programs run so far.
+    pub fn runs(&self) -> Vec<Run> {
+        self.state.borrow().runs.clone()
+    }
+
+    /// A copy of the entries at `paths` on `fs`, with everything below them and the directories
+    /// above them, to try out changes on. The entries at `shallow_paths` are copied with the
+    /// directories above them but without what is below them, for when only their metadata
+    /// matters. A symlink among them, or above them, is copied along with what it points to.
+    ///
+    /// Entries keep their mode, owner, times, extended attributes and hard links, and a
+    /// directory on another device than its parent becomes a [mount](MemoryFs::mount). The copy
+    /// acts as the owner of the first of `paths`, or of its closest existing parent, and as root
+    /// if `fs` does.
+    pub fn snapshot(
+        fs: &dyn Filesystem,
+        paths: &[PathBuf],
+        shallow_paths: &[PathBuf],
+    ) -> io::Result<MemoryFs> {
+        let mut memory = MemoryFs::new();
+        let state = memory.state.get_mut();
+        state.root = fs.is_root();
+        if let Some(owner) = paths.first().and_then(|path| {
+            path.ancestors()
+                .find_map(|path| fs.symlink_metadata(path).ok())
+        }) {
+            (state.uid, state.gid) = (owner.uid, owner.gid);
+        }
+        let root = fs.symlink_metadata(Path::new("/"))?;
+        let ino = state.lookup(Path::new("/"))?;
+        state.copy_metadata(ino, &root);
+        state.mounts.insert(PathBuf::from("/"), root.dev);
+        let mut snapshot = Snapshot {
+            fs,
+            state,
+            copied: BTreeMap::new(),
+            trees: Vec::new(),
+        };
+        for path in paths {
+            snapshot.add(path, true)?;
+        }
+        for path in shallow_paths {
+            snapshot.add(path, false)?;
+        }
+        Ok(memory)
+    }
+}
+
+impl Data {
+    fn is_dir(&self) -> bool {
+        matches!(self, Data::Dir)
+    }
+
+    fn file_type(&self) -> FileType {
+        match self {
+            Data::File(_) => FileType::File,
+            Data::Dir => FileType::Dir,
+            Data::Symlink(_) => FileType::Symlink,
+            Data::Special(file_type) => *file_type,
+        }
+    }
+}
+
+impl State {
+    fn tick(&mut self) -> i64 {
+        self.clock += 1;
+        self.clock
+    }
+
+    fn add_inode(&mut self, data: Data, mode: u32) -> u64 {
+        let ino = self.next_ino;
+        self.next_ino += 1;
+        let clock = self.tick();
+        self.inodes.insert(
+            ino,
+            Inode {
+                data,
+                mode,
+                uid: self.uid,
+                gid: self.gid,
+                nlink: 0,
+                atime: clock,
+                atime_nsec: 0,
+                mtime: clock,
+                mtime_nsec: 0,
+                xattrs: BTreeMap::new(),
+            },
+        );
+        ino
+    }
+
+    fn lookup(&self, path: &Path) -> io::Result<u64> {
+        self.entries
+            .get(path)
+            .copied()
+            .ok_or_else(|| error(libc::ENOENT))
+    }
+
+    fn inode(&self, path: &Path) -> io::Result<&Inode> {
+        Ok(&self.inodes[&self.lookup(path)?])
+    }
+
+    fn inode_mut(&mut self, path: &Path) -> io::Result<&mut Inode> {
+        let ino = self.lookup(path)?;
+        Ok(self.inodes.get_mut(&ino).unwrap())
+    }
+
+    fn dev(&self, path: &Path) -> u64 {
+        self.mounts
+            .iter()
+            .filter(|(mount, _)| path.starts_with(mount))
+            .max_by_key(|(mount, _)| mount.as_os_str().len())
+            .map_or(1, |(_, dev)| *dev)
+    }
+
+    /// Turn `path` into the path of the entry it names, resolving symlinks on the way. The last
+    /// component is only resolved if `follow` is set, and doesn't have to exist.
+    fn resolve(&self, path: &Path, follow: bool) -> io::Result<PathBuf> {
+        let mut resolved = PathBuf::from("/");
+        let mut pending: VecDeque<Component> = path.components().collect();
+        let mut links = 0;
+        while let Some(component) = pending.pop_front() {
+            let name = match component {
+                Component::RootDir => {
+                    resolved = PathBuf::from("/");
+                    continue;
+                }
+                Component::CurDir | Component::Prefix(_) => continue,
+                Component::ParentDir => {
+                    resolved.pop();
+                    continue;
+                }
+                Component::Normal(name) => name,
+            };
+            let candidate = resolved.join(name);
+            let is_last = pending.is_empty();
+            let Some(ino) = self.entries.get(&candidate) else {
+                if is_last {
+                    return Ok(candidate);
+                }
+                return Err(error(libc::ENOENT));
+            };
+            match &self.inodes[ino].data {
+                Data::Symlink(target) if !is_last || follow => {
+                    links += 1;
+                    if links > MAX_SYMLINKS {
+                        return Err(error(libc::ELOOP));
+                    }
+                    for component in target.components().rev() {
+                        pending.push_front(component);
+                    }
+                }
+                Data::Dir => resolved = candidate,
+                _ if is_last => resolved = candidate,
+                _ => return Err(error(libc::ENOTDIR)),
+            }
+        }
+        Ok(resolved)
+    }
+
+    /// Fail unless the current user has the permission `bits` (owner bits) on `path`.
+    fn check_access(&self, path: &Path, bits: u32) -> io::Result<()> {
+        if self.root || self.inode(path)?.mode & bits == bits {
+            Ok(())
+        } else {
+            Err(error(libc::EACCES))
+        }
+    }
+
+    /// Check that entries may be added to or removed from the directory containing `path`.
+    fn check_parent(&self, path: &Path) -> io::Result<PathBuf> {
+        let parent = path.parent().ok_or_else(|| error(libc::EBUSY))?;
+        if !self.inode(parent)?.data.is_dir() {
+            return Err(error(libc::ENOTDIR));
+        }
+        self.check_access(parent, 0o300)?;
+        Ok(parent.to_owned())
+    }
+
+    /// Record that the directory containing `path` changed.
+    fn touch_parent(&mut self, path: &Path) {
+        let clock = self.tick();
+        if let Some(inode) = path.parent().and_then(|parent| self.inode_mut(parent).ok()) {
+            inode.mtime = clock;
+            inode.mtime_nsec = 0;
+        }
+    }
+
+    fn link(&mut self, path: &Path, ino: u64) -> io::Result<()> {
+        if self.entries.contains_key(path) {
+            return Err(error(libc::EEXIST));
+        }
+        self.check_parent(path)?;
+        self.entries.insert(path.to_owned(), ino);
+        self.inodes.get_mut(&ino).unwrap().nlink += 1;
+        self.touch_parent(path);
+        Ok(())
+    }
+
+    fn unlink(&mut self, path: &Path) -> io::Result<()> {
+        self.check_parent(path)?;
+        let ino = self
+            .entries
+            .remove(path)
+            .ok_or_else(|| error(libc::ENOENT))?;
+        let inode = self.inodes.get_mut(&ino).unwrap();
+        inode.nlink -= 1;
+        if inode.nlink == 0 {
+            self.inodes.remove(&ino);
+        }
+        self.touch_parent(path);
+        Ok(())
+    }
+
+    fn create(&mut self, path: &Path, data: Data, mode: u32) -> io::Result<u64> {
+        if self.entries.contains_key(path) {
+            return Err(error(libc::EEXIST));
+        }
+        self.check_parent(path)?;
+        let ino = self.add_inode(data, mode);
+        self.link(path, ino)?;
+        Ok(ino)
+    }
+
+    fn children(&self, dir: &Path) -> Vec<PathBuf> {
+        self.entries
+            .range(dir.to_owned()..)
+            .map(|(path, _)| path)
+            .skip(1)
+            .take_while(|path| path.starts_with(dir))
+            .filter(|path| path.parent() == Some(dir))
+            .cloned()
+            .collect()
+    }
+
+    fn metadata(&self, path: &Path) -> io::Result<Metadata> {
+        let ino = self.lookup(path)?;
+        let inode = &self.inodes[&ino];
+        let len = match &inode.data {
+            Data::File(data) => data.len(),
+            Data::Symlink(target) => target.as_os_str().len(),
+            Data::Dir | Data::Special(_) => 0,
+        };
+        Ok(Metadata {
+            file_type: inode.data.file_type(),
+            mode: inode.mode,
+            uid: inode.uid,
+            gid: inode.gid,
+            dev: self.dev(path),
+            ino,
+            nlink: inode.nlink,
+            len: len as u64,
+            atime: inode.atime,
+            atime_nsec: inode.atime_nsec,
+            mtime: inode.mtime,
+            mtime_nsec: inode.mtime_nsec,
+        })
+    }
+
+    /// Give the inode `ino` the mode, owner and times of `metadata`.
+    fn copy_metadata(&mut self, ino: u64, metadata: &Metadata) {
+        let inode = self.inodes.get_mut(&ino).unwrap();
+        inode.mode = metadata.mode;
+        (inode.uid, inode.gid) = (metadata.uid, metadata.gid);
+        (inode.atime, inode.atime_nsec) = (metadata.atime, metadata.atime_nsec);
+        (inode.mtime, inode.mtime_nsec) = (metadata.mtime, metadata.mtime_nsec);
+    }
+
+    fn chown_allowed(&self, inode: &Inode, uid: u32, gid: u32) -> bool {
+        self.root || (inode.uid == self.uid && uid == self.uid && gid == self.gid)
+    }
+}
+
+impl Filesystem for MemoryFs {
+    fn metadata(&self, path: &Path) -> io::Result<Metadata> {
+        let state = self.state.borrow();
+        state.metadata(&state.resolve(path, true)?)
+    }
+
+    fn symlink_metadata(&self, path: &Path) -> io::Result<Metadata> {
+        let state = self.state.borrow();
+        state.metadata(&state.resolve(path, false)?)
+    }
+
+    fn read_dir(&self, path: &Path) -> io::Result<Vec<PathBuf>> {
+        let state = self.state.borrow();
+        let dir = state.resolve(path, true)?;
+        if !state.inode(&dir)?.data.is_dir() {
+            return Err(error(libc::ENOTDIR));
+        }
+        state.check_access(&dir, 0o400)?;
+        // Entries are listed under the path we were given, like `fs::read_dir` does.
+        Ok(state
+            .children(&dir)
+            .iter()
+            .map(|child| path.join(child.file_name().unwrap()))
+            .collect())
+    }
+
+    fn read_link(&self, path: &Path) -> io::Result<PathBuf> {
+        let state = self.state.borrow();
+        match &state.inode(&state.resolve(path, false)?)?.data {
+            Data::Symlink(target) => Ok(target.clone()),
+            _ => Err(error(libc::EINVAL)),
+        }
+    }
+
+    fn canonicalize(&self, path: &Path) -> io::Result<PathBuf> {
+        let state = self.state.borrow();
+        let resolved = state.resolve(path, true)?;
+        state.lookup(&resolved)?;
+        Ok(resolved)
+    }
+
+    fn open(&self, path: &Path) -> io::Result<Box<dyn Read + '_>> {
+        let state = self.state.borrow();
+        let path = state.resolve(path, true)?;
+        state.check_access(&path, 0o400)?;
+        match &state.inode(&path)?.data {
+            Data::File(data) => Ok(Box::new(io::Cursor::new(data.clone()))),
+            Data::Dir => Err(error(libc::EISDIR)),
+            _ => Err(error(libc::ENXIO)),
+        }
+    }
+
+    fn create_dir(&self, path: &Path) -> io::Result<()> {
+        let mut state = self.state.borrow_mut();
+        let path = state.resolve(path, false)?;
+        state.create(&path, Data::Dir, 0o755).map(|_| ())
+    }
+
+    fn remove_file(&self, path: &Path) -> io::Result<()> {
+        let mut state = self.state.borrow_mut();
+        let path = state.resolve(path, false)?;
+        if state.inode(&path)?.data.is_dir() {
+            return Err(error(libc::EISDIR));
+        }
+        state.unlink(&path)
+    }
+
+    fn remove_dir(&self, path: &Path) -> io::Result<()> {
+        let mut state = self.state.borrow_mut();
+        let path = state.resolve(path, false)?;
+        if !state.inode(&path)?.data.is_dir() {
+            return Err(error(libc::ENOTDIR));
+        }
+        if !state.children(&path).is_empty() {
+            return Err(error(libc::ENOTEMPTY));
+        }
+        state.unlink(&path)
+    }
+
+    fn rename(&self, from: &Path, to: &Path) -> io::Result<()> {
+        let mut state = self.state.borrow_mut();
+        let from = state.resolve(from, false)?;
+        let to = state.resolve(to, false)?;
+        let ino = state.lookup(&from)?;
+        if from == to {
+            return Ok(());
+        }
+        if to.starts_with(&from) {
+            return Err(error(libc::EINVAL));
+        }
+        if state.dev(&from) != state.dev(&to) {
+            return Err(error(libc::EXDEV));
+        }
+        state.check_parent(&from)?;
+        state.check_parent(&to)?;
+        let is_dir = state.inodes[&ino].data.is_dir();
+        if let Ok(existing) = state.lookup(&to) {
+            match (is_dir, state.inodes[&existing].data.is_dir()) {
+                (true, true) if !state.children(&to).is_empty() => {
+                    return Err(error(libc::ENOTEMPTY))
+                }
+                (true, false) => return Err(error(libc::ENOTDIR)),
+                (false, true) => return Err(error(libc::EISDIR)),
+                _ => state.unlink(&to)?,
+            }
+        }
+        let moved: Vec<(PathBuf, u64)> = state
+            .entries
+            .range(from.clone()..)
+            .take_while(|(path, _)| path.starts_with(&from))
+            .map(|(path, ino)| (path.clone(), *ino))
+            .collect();
+        for (path, ino) in moved {
+            state.entries.remove(&path);
+            let relative = path.strip_prefix(&from).unwrap();
+            let new_path = if relative.as_os_str().is_empty() {
+                to.clone()
+            } else {
+                to.join(relative)
+            };
+            state.entries.insert(new_path, ino);
+        }
+        state.touch_parent(&from);
+        state.touch_parent(&to);
+        Ok(())
+    }
+
+    fn symlink(&self, target: &Path, path: &Path) -> io::Result<()> {
+        let mut state = self.state.borrow_mut();
+        let path = state.resolve(path, false)?;
+        state
+            .create(&path, Data::Symlink(target.to_owned()), 0o777)
+            .map(|_| ())
+    }
+
+    fn copy_file(&self, from: &Path, to: &Path) -> io::Result<()> {
+        let contents = self.read(from)?;
+        let mode = self.metadata(from)?.mode;
+        self.write(to, contents)?;
+        self.set_mode(to, mode)
+    }
+
+    fn write_file(&self, path: &Path, contents: &[u8]) -> io::Result<()> {
+        self.write(path, contents)
+    }
+
+    fn mkfifo(&self, path: &Path) -> io::Result<()> {
+        let mut state = self.state.borrow_mut();
+        let path = state.resolve(path, false)?;
+        state
+            .create(&path, Data::Special(FileType::Fifo), 0o600)
+            .map(|_| ())
+    }
+
+    fn set_mode(&self, path: &Path, mode: u32) -> io::Result<()> {
+        let mut state = self.state.borrow_mut();
+        let path = state.resolve(path, true)?;
+        let (root, uid) = (state.root, state.uid);
+        let inode = state.inode_mut(&path)?;
+        if !root && inode.uid != uid {
+            return Err(error(libc::EPERM));
+        }
+        inode.mode = mode & 0o7777;
+        Ok(())
+    }
+
+    fn set_owner(&self, path: &Path, uid: u32, gid: u32) -> io::Result<()> {
+        let mut state = self.state.borrow_mut();
+        let path = state.resolve(path, false)?;
+        if !state.chown_allowed(state.inode(&path)?, uid, gid) {
+            return Err(error(libc::EPERM));
+        }
+        let inode = state.inode_mut(&path)?;
+        inode.uid = uid;
+        inode.gid = gid;
+        Ok(())
+    }
+
+    fn set_times(&self, path: &Path, times: &Metadata) -> io::Result<()> {
+        let mut state = self.state.borrow_mut();
+        let path = state.resolve(path, false)?;
+        let inode = state.inode_mut(&path)?;
+        inode.atime = times.atime;
+        inode.atime_nsec = times.atime_nsec;
+        inode.mtime = times.mtime;
+        inode.mtime_nsec = times.mtime_nsec;
+        Ok(())
+    }
+
+    fn list_xattrs(&self, path: &Path) -> io::Result<Vec<OsString>> {
+        let state = self.state.borrow();
+        let inode = state.inode(&state.resolve(path, false)?)?;
+        Ok(inode.xattrs.keys().cloned().collect())
+    }
+
+    fn get_xattr(&self, path: &Path, name: &OsStr) -> io::Result<Option<Vec<u8>>> {
+        let state = self.state.borrow();
+        let inode = state.inode(&state.resolve(path, false)?)?;
+        Ok(inode.xattrs.get(name).cloned())
+    }
+
+    fn set_xattr(&self, path: &Path, name: &OsStr, value: &[u8]) -> io::Result<()> {
+        let mut state = self.state.borrow_mut();
+        let path = state.resolve(path, false)?;
+        state
+            .inode_mut(&path)?
+            .xattrs
+            .insert(name.to_owned(), value.to_vec());
+        Ok(())
+    }
+
+    fn is_root(&self) -> bool {
+        self.state.borrow().root
+    }
+
+    fn var(&self, name: &str) -> Option<OsString> {
+        self.state.borrow().vars.get(name).cloned()
+    }
+
+    fn run(&self, program: &Path, args: &[OsString], dir: Option<&Path>) -> io::Result<Output> {
+        let mut state = self.state.borrow_mut();
+        state.runs.push(Run {
+            program: program.to_owned(),
+            args: args.to_vec(),
+            dir: dir.map(Path::to_owned),
+        });
+        let stdout = if args == [OsString::from("--version")] {
+            format!("stow (GNU Stow) version {}\n", state.stow_version)
+        } else {
+            String::new()
+        };
+        Ok(Output {
+            status: ExitStatus::from_raw(0),
+            stdout: stdout.into_bytes(),
+            stderr: Vec::new(),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_memory_fs_files_and_dirs() {
+        let fs = MemoryFs::new();
+        fs.create_dir_all(Path::new("/home/user/.config")).unwrap();
+        fs.write(Path::new("/home/user/.vimrc"), "set nu").unwrap();
+
+        assert_eq!(
+            fs.read_to_string(Path::new("/home/user/.vimrc")).unwrap(),
+            "set nu"
+        );
+        assert_eq!(
+            fs.read_dir(Path::new("/home/user")).unwrap(),
+            vec![
+                PathBuf::from("/home/user/.config"),
+                PathBuf::from("/home/user/.vimrc")
+            ]
+        );
+        assert_eq!(
+            fs.create_dir(Path::new("/home/user/.config"))
+                .unwrap_err()
+                .raw_os_error(),
+            Some(libc::EEXIST)
+        );
+        assert_eq!(
+            fs.remove_dir(Path::new("/home/user"))
+                .unwrap_err()
+                .raw_os_error(),
+            Some(libc::ENOTEMPTY)
+        );
+        assert!(fs.write(Path::new("/missing/file"), "").is_err());
+    }
+
+    #[test]
+    fn test_memory_fs_symlinks() {
+        let fs = MemoryFs::new();
+        fs.create_dir_all(Path::new("/dotfiles/nvim/.config/nvim"))
+            .unwrap();
+        fs.write(Path::new("/dotfiles/nvim/.config/nvim/init.lua"), "")
+            .unwrap();
+        fs.create_dir(Path::new("/home")).unwrap();
+        fs.symlink(
+            Path::new("../dotfiles/nvim/.config"),
+            Path::new("/home/.config"),
+        )
+        .unwrap();
+        fs.symlink(Path::new("dangling"), Path::new("/home/link"))
+            .unwrap();
+
+        let through_link = Path::new("/home/.config/nvim/init.lua");
+        assert!(fs.is_file(through_link));
+        assert_eq!(
+            fs.canonicalize(through_link).unwrap(),
+            Path::new("/dotfiles/nvim/.config/nvim/init.lua")
+        );
+        assert!(fs.is_symlink(Path::new("/home/.config")));
+        assert!(fs.is_dir(Path::new("/home/.config")));
+        assert_eq!(
+            fs.read_dir(Path::new("/home/.config")).unwrap(),
+            vec![PathBuf::from("/home/.config/nvim")]
+        );
+        // A dangling link exists, but can't be followed.
+        assert!(fs.symlink_metadata(Path::new("/home/link")).is_ok());
+        assert!(!fs.exists(Path::new("/home/link")));
+
+        fs.symlink(Path::new("loop"), Path::new("/loop")).unwrap();
+        assert_eq!(
+            fs.metadata(Path::new("/loop")).unwrap_err().raw_os_error(),
+            Some(libc::ELOOP)
+        );
+    }
+
+    #[test]
+    fn test_memory_fs_permissions() {
+        let fs = MemoryFs::new();
+        fs.create_dir(Path::new("/locked")).unwrap();
+        fs.write(Path::new("/locked/secret"), "key").unwrap();
+        fs.set_mode(Path::new("/locked/secret"), 0o000).unwrap();
+        fs.set_mode(Path::new("/locked"), 0o500).unwrap();
+
+        assert_eq!(
+            fs.read(Path::new("/locked/secret"))
+                .unwrap_err()
+                .raw_os_error(),
+            Some(libc::EACCES)
+        );
+        assert!(fs.write(Path::new("/locked/new"), "").is_err());
+        assert!(fs.remove_file(Path::new("/locked/secret")).is_err());
+        assert!(fs.set_owner(Path::new("/locked"), 0, 0).is_err());
+
+        fs.set_root(true);
+        assert_eq!(fs.read(Path::new("/locked/secret")).unwrap(), b"key");
+        fs.set_owner(Path::new("/locked"), 0, 0).unwrap();
+        assert_eq!(fs.metadata(Path::new("/locked")).unwrap().uid, 0);
+    }
+
+    #[test]
+    fn test_memory_fs_rename() {
+        let fs = MemoryFs::new();
+        fs.create_dir_all(Path::new("/a/sub")).unwrap();
+        fs.write(Path::new("/a/sub/file"), "content").unwrap();
+        fs.create_dir(Path::new("/mnt")).unwrap();
+        fs.mount(Path::new("/mnt"));
+
+        fs.rename(Path::new("/a"), Path::new("/b")).unwrap();
+        assert!(!fs.exists(Path::new("/a")));
+        assert_eq!(fs.read(Path::new("/b/sub/file")).unwrap(), b"content");
+        assert_eq!(
+            fs.rename(Path::new("/b"), Path::new("/mnt/b"))
+                .unwrap_err()
+                .raw_os_error(),
+            Some(libc::EXDEV)
+        );
+        assert_eq!(
+            fs.rename(Path::new("/b"), Path::new("/b/sub/c"))
+                .unwrap_err()
+                .raw_os_error(),
+            Some(libc::EINVAL)
+        );
+    }
+
+    #[test]
+    fn test_memory_fs_hard_links() {
+        let fs = MemoryFs::new();
+        fs.write(Path::new("/file"), "content").unwrap();
+        fs.hard_link(Path::new("/file"), Path::new("/other"))
+            .unwrap();
+        assert_eq!(fs.metadata(Path::new("/file")).unwrap().nlink, 2);
+
+        fs.remove_file(Path::new("/file")).unwrap();
+        assert_eq!(fs.metadata(Path::new("/other")).unwrap().nlink, 1);
+        assert_eq!(fs.read(Path::new("/other")).unwrap(), b"content");
+    }
+
+    #[test]
+    fn test_snapshot() {
+        let temp_dir = tempfile::TempDir::new().unwrap();
+        let real = temp_dir.path().canonicalize().unwrap();
+        let config = real.join("config");
+        fs::create_dir_all(config.join("lua")).unwrap();
+        fs::write(config.join("init.lua"), "vim.o.number = true").unwrap();
+        fs::set_permissions(config.join("init.lua"), fs::Permissions::from_mode(0o600)).unwrap();
+        fs::hard_link(config.join("init.lua"), config.join("lua/init.lua")).unwrap();
+        fs::create_dir(real.join("elsewhere")).unwrap();
+        fs::write(real.join("elsewhere/file"), "").unwrap();
+        fs::write(real.join("unrelated"), "").unwrap();
+        fs::create_dir(real.join("shallow")).unwrap();
+        fs::write(real.join("shallow/file"), "").unwrap();
+        symlink(real.join("elsewhere"), real.join("link")).unwrap();
+
+        let paths = [config.clone(), real.join("link"), real.join("missing/file")];
+        let memory = MemoryFs::snapshot(&RealFs, &paths, &[real.join("shallow")]).unwrap();
+
+        assert_eq!(
+            memory.read(&config.join("init.lua")).unwrap(),
+            b"vim.o.number = true"
+        );
+        let metadata = memory.metadata(&config.join("lua/init.lua")).unwrap();
+        assert_eq!((metadata.mode, metadata.nlink), (0o600, 2));
+        assert_eq!(
+            metadata.mtime,
+            RealFs.metadata(&config.join("init.lua")).unwrap().mtime
+        );
+        assert!(memory.is_file(&real.join("link/file")));
+        assert!(memory.is_dir(&real));
+        assert!(!memory.exists(&real.join("unrelated")));
+        assert!(!memory.exists(&real.join("missing")));
+        assert!(memory.is_dir(&real.join("shallow")));
+        assert!(!memory.exists(&real.join("shallow/file")));
+    }
+
+    #[test]
+    fn test_memory_fs_records_runs() {
+        let fs = MemoryFs::new();
+        fs.set_stow_version("2.4.0");
+        let output = fs
+            .run(Path::new("stow"), &["--version".into()], None)
+            .unwrap();
+        assert!(output.status.success());
+        assert_eq!(output.stdout, b"stow (GNU Stow) version 2.4.0\n");
+        fs.run(Path::new("stow"), &["vim".into()], Some(Path::new("/")))
+            .unwrap();
+        assert_eq!(fs.runs().len(), 2);
+        assert_eq!(fs.runs()[1].dir, Some(PathBuf::from("/")));
+    }
+}
@@ -0,0 +1,71 @@
+//! The library behind the `stowsave` command line tool.
+//!
+//! Saving a path happens in two steps. [`plan_save`] checks that the path can be saved and
+//! returns a [`Plan`], the list of [`Command`]s that back the path up, move it into the stow
+//! package and run stow. Nothing on disk changes until [`Plan::execute`] runs the commands,
+//! reporting progress to an [`Observer`].
+//!
+//! ```no_run
+//! use std::path::Path;
+//!
+//! use stowsave::{plan_save, SaveOptions};
+//!
+//! let plan = plan_save(
+//!     Path::new("/home/user/.vimrc"),
+//!     Path::new("/home/user/dotfiles/vim"),
+//!     &SaveOptions::default(),
+//! )?;
+//! for command in &plan.commands {
+//!     println!("{:?}", command);
+//! }
+//! plan.execute(&mut ())?;
+//! # Ok::<(), anyhow::Error>(())
+//! ```
+//!
+//! Planning and executing only touch files through a [`Filesystem`]. The functions above use
+//! [`RealFs`]; [`plan_save_on`], [`plan_unsave_on`], [`plan_unfold_on`] and
+//! [`Plan::execute_on`] take any other implementation, such as a [`MemoryFs`] that shows exactly
+//! what a plan would do without touching the disk or running stow. [`Plan::simulate`] executes a plan on a
+//! [`MemoryFs::snapshot`] of the files it touches, which is how `--dry-run` tries plans out.
+//! Planning reads the environment, such as `$HOME` and `$STOW_DIR`, through the [`Filesystem`]
+//! too, so a [`MemoryFs`] only sees the variables given to [`MemoryFs::set_var`].
+//!
+//! Progress is reported through the [`log`] crate: each command's start and end at debug level,
+//! and details such as the output of stow at debug or trace level.
+//!
+//! [`SaveOptions`], [`Plan`], [`Command`], [`Error`](enum@Error) and [`StowSaveError`] are
+//! `#[non_exhaustive]`, so that new options, fields, commands and errors don't break callers.
+//! Match them with a wildcard arm, and build options with [`SaveOptions::new`].
+//!
+//! Planning and executing fail with an [`Error`], whose variant tells validation errors,
+//! conflicts, I/O failures and stow failures apart, and whether a failed plan was undone. The
+//! error behind it can be downcast, for example to a [`StowSaveError`] raised by the checks or a
+//! [`stow::StowError`] raised by stow.
+pub mod audit;
+pub mod checks;
+pub mod command;
+pub mod config;
+pub mod copy;
+pub mod doctor;
+pub mod error;
+pub mod filesystem;
+pub mod lock;
+mod plan;
+pub mod report;
+pub mod secrets;
+pub mod selection;
+pub mod stow;
+mod transfer;
+pub mod usage;
+mod util;
+
+pub use checks::StowSaveError;
+pub use command::{Command, CommandImpl};
+pub use error::Error;
+pub use filesystem::{Filesystem, MemoryFs, RealFs};
+pub use plan::{
+    find_folded_stow_dir, find_folded_stow_dir_on, find_saved_path, find_saved_path_on,
+    find_saved_stow_dir, find_saved_stow_dir_on, find_stow_dir, find_stow_dir_on, plan_save,
+    plan_save_on, plan_unfold, plan_unfold_on, plan_unsave, plan_unsave_on, Observer, Plan,
+    SaveOptions, SavedPath, SymlinkMode,
+};
@@ -0,0 +1,147 @@
+//! An advisory lock on a stow directory, so that two runs of stowsave don't move files into it
+//! and run stow on its packages at the same time.
+//!
+//! The lock is an `flock` on a file in `$XDG_STATE_HOME/stowsave/locks`, or
+//! `~/.local/state/stowsave/locks`, named after a hash of the stow directory. Nothing is added to
+//! the stow directory itself, which is often a git repository. The process that holds the lock
+//! writes its PID into the file, so that others can tell the user who they are waiting for.
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::os::fd::AsRawFd;
+use std::path::{Path, PathBuf};
+use std::process;
+
+use anyhow::{anyhow, Context};
+use sha2::{Digest, Sha256};
+
+use crate::error::Error;
+use crate::filesystem::RealFs;
+use crate::util;
+
+/// Holds the lock on a stow directory until dropped.
+#[derive(Debug)]
+pub struct StowLock {
+    // Closing the file releases the lock.
+    _file: File,
+}
+
+impl StowLock {
+    /// Lock `stow_dir`. If another process holds the lock, wait for it if `wait` is set, and fail
+    /// with [`Error::Locked`] otherwise.
+    pub fn acquire(stow_dir: &Path, wait: bool) -> Result<StowLock, Error> {
+        let path = lock_path(stow_dir).ok_or_else(|| {
+            anyhow!("Cannot tell where to put the lock file; set $HOME or $XDG_STATE_HOME")
+        })?;
+        StowLock::acquire_at(&path, stow_dir, wait)
+    }
+
+    fn acquire_at(path: &Path, stow_dir: &Path, wait: bool) -> Result<StowLock, Error> {
+        let open = || -> io::Result<File> {
+            fs::create_dir_all(path.parent().unwrap())?;
+            OpenOptions::new()
+                .read(true)
+                .write(true)
+                .create(true)
+                .truncate(false)
+                .open(path)
+        };
+        let mut file =
+            open().with_context(|| format!("Failed to open lock file '{}'", path.display()))?;
+        if !flock(&file, libc::LOCK_EX | libc::LOCK_NB)
+            .with_context(|| format!("Failed to lock '{}'", path.display()))?
+        {
+            let pid = read_pid(&mut file);
+            if !wait {
+                return Err(Error::Locked {
+                    stow_dir: stow_dir.to_owned(),
+                    pid,
+                });
+            }
+            log::info!(
+                "Waiting for another stowsave{} to finish with '{}'",
+                pid.map(|pid| format!(" (PID {})", pid)).unwrap_or_default(),
+                stow_dir.display()
+            );
+            flock(&file, libc::LOCK_EX)
+                .with_context(|| format!("Failed to lock '{}'", path.display()))?;
+        }
+        write_pid(&mut file)
+            .with_context(|| format!("Failed to write to lock file '{}'", path.display()))?;
+        Ok(StowLock { _file: file })
+    }
+}
+
+/// The lock file for `stow_dir`.
+fn lock_path(stow_dir: &Path) -> Option<PathBuf> {
+    let state_dir = util::state_dir(&RealFs)?;
+    let hash = Sha256::digest(stow_dir.as_os_str().as_encoded_bytes());
+    let name: String = hash[..8]
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect();
+    Some(state_dir.join("locks").join(format!("{}.lock", name)))
+}
+
+/// Apply `operation` to `file`. Returns `false` if a non-blocking lock is held elsewhere.
+fn flock(file: &File, operation: libc::c_int) -> io::Result<bool> {
+    loop {
+        // SAFETY: the descriptor stays valid while `file` is borrowed.
+        if unsafe { libc::flock(file.as_raw_fd(), operation) } == 0 {
+            return Ok(true);
+        }
+        let err = io::Error::last_os_error();
+        match err.kind() {
+            io::ErrorKind::WouldBlock => return Ok(false),
+            io::ErrorKind::Interrupted => continue,
+            _ => return Err(err),
+        }
+    }
+}
+
+fn read_pid(file: &mut File) -> Option<u32> {
+    let mut contents = String::new();
+    file.seek(SeekFrom::Start(0)).ok()?;
+    file.read_to_string(&mut contents).ok()?;
+    contents.trim().parse().ok()
+}
+
+fn write_pid(file: &mut File) -> io::Result<()> {
+    file.set_len(0)?;
+    file.seek(SeekFrom::Start(0))?;
+    writeln!(file, "{}", process::id())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_lock_is_exclusive() {
+        let temp_dir = tempfile::TempDir::new().unwrap();
+        let path = temp_dir.path().join("locks").join("dotfiles.lock");
+        let stow_dir = Path::new("/home/user/dotfiles");
+
+        let lock = StowLock::acquire_at(&path, stow_dir, false).unwrap();
+        assert_eq!(
+            fs::read_to_string(&path).unwrap(),
+            format!("{}\n", process::id())
+        );
+
+        // flock locks belong to open files, so a second open conflicts even in one process.
+        match StowLock::acquire_at(&path, stow_dir, false) {
+            Err(Error::Locked { pid, .. }) => assert_eq!(pid, Some(process::id())),
+            other => panic!("unexpected result {:?}", other),
+        }
+
+        drop(lock);
+        StowLock::acquire_at(&path, stow_dir, false).unwrap();
+    }
+
+    #[test]
+    fn test_lock_path_depends_on_stow_dir() {
+        assert_ne!(
+            lock_path(Path::new("/home/user/dotfiles")),
+            lock_path(Path::new("/home/user/other"))
+        );
+    }
+}
@@ -0,0 +1,152 @@
+//! The logger of the command line tool.
+//!
+//! Messages go to stderr so that stdout only carries output that scripts may want to parse.
+//! Errors, warnings and debug messages are prefixed with their level, info messages are printed
+//! as they are. With `--log-file`, messages are also appended to a file with a timestamp, down
+//! to debug level even when the console shows less, so that the file always records when each
+//! command started and finished.
+use std::fmt;
+use std::fs::{File, OpenOptions};
+use std::io::{self, Write};
+use std::path::Path;
+use std::sync::Mutex;
+use std::time::SystemTime;
+
+use log::{Level, LevelFilter, Log, Metadata, Record, SetLoggerError};
+
+/// The lowest level that is written to the log file.
+const FILE_LEVEL: LevelFilter = LevelFilter::Debug;
+
+pub struct Logger {
+    level: LevelFilter,
+    file: Option<Mutex<File>>,
+}
+
+impl Logger {
+    /// A logger that prints messages up to `level` and appends them to `file`, if given.
+    pub fn new(level: LevelFilter, file: Option<&Path>) -> io::Result<Logger> {
+        let file = match file {
+            Some(path) => Some(Mutex::new(
+                OpenOptions::new().create(true).append(true).open(path)?,
+            )),
+            None => None,
+        };
+        Ok(Logger { level, file })
+    }
+
+    /// Install the logger for the `log` macros.
+    pub fn init(self) -> Result<(), SetLoggerError> {
+        log::set_max_level(self.max_level());
+        log::set_boxed_logger(Box::new(self))
+    }
+
+    fn max_level(&self) -> LevelFilter {
+        match self.file {
+            Some(_) => self.level.max(FILE_LEVEL),
+            None => self.level,
+        }
+    }
+}
+
+impl Log for Logger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        // Dependencies such as globset log their internals, which users don't need to see.
+        metadata.target().starts_with("stowsave") && metadata.level() <= self.max_level()
+    }
+
+    fn log(&self, record: &Record) {
+        if !self.enabled(record.metadata()) {
+            return;
+        }
+        if record.level() <= self.level {
+            eprintln!("{}", console_line(record.level(), record.args()));
+        }
+        if let Some(file) = &self.file {
+            if record.level() <= FILE_LEVEL {
+                let mut file = file.lock().unwrap_or_else(|err| err.into_inner());
+                // A log file that can't be written to shouldn't stop the save.
+                let _ = file.write_all(
+                    file_line(SystemTime::now(), record.level(), record.args()).as_bytes(),
+                );
+            }
+        }
+    }
+
+    fn flush(&self) {
+        if let Some(file) = &self.file {
+            let _ = file.lock().unwrap_or_else(|err| err.into_inner()).flush();
+        }
+    }
+}
+
+fn console_line(level: Level, message: &fmt::Arguments) -> String {
+    match level {
+        Level::Error => format!("error: {}", message),
+        Level::Warn => format!("warning: {}", message),
+        Level::Info => message.to_string(),
+        Level::Debug => format!("debug: {}", message),
+        Level::Trace => format!("trace: {}", message),
+    }
+}
+
+fn file_line(time: SystemTime, level: Level, message: &fmt::Arguments) -> String {
+    format!(
+        "{} {:<5} {}\n",
+        humantime::format_rfc3339_millis(time),
+        level,
+        message
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, UNIX_EPOCH};
+
+    use super::*;
+
+    #[test]
+    fn test_console_line() {
+        assert_eq!(
+            console_line(Level::Warn, &format_args!("stow is old")),
+            "warning: stow is old"
+        );
+        assert_eq!(
+            console_line(Level::Info, &format_args!("Leaving in place: x")),
+            "Leaving in place: x"
+        );
+    }
+
+    #[test]
+    fn test_file_line() {
+        let time = UNIX_EPOCH + Duration::from_millis(1_700_000_000_123);
+        assert_eq!(
+            file_line(time, Level::Info, &format_args!("Saved")),
+            "2023-11-14T22:13:20.123Z INFO  Saved\n"
+        );
+    }
+
+    #[test]
+    fn test_log_file_records_debug_messages() {
+        let temp_dir = tempfile::TempDir::new().unwrap();
+        let path = temp_dir.path().join("stowsave.log");
+        let logger = Logger::new(LevelFilter::Warn, Some(&path)).unwrap();
+        assert_eq!(logger.max_level(), LevelFilter::Debug);
+
+        for (level, target, message) in [
+            (Level::Debug, "stowsave::plan", "Finished"),
+            (Level::Trace, "stowsave::plan", "Details"),
+            (Level::Info, "globset", "Built glob set"),
+        ] {
+            logger.log(
+                &Record::builder()
+                    .level(level)
+                    .target(target)
+                    .args(format_args!("{}", message))
+                    .build(),
+            );
+        }
+        let contents = std::fs::read_to_string(&path).unwrap();
+        assert!(contents.ends_with(" DEBUG Finished\n"), "{}", contents);
+        assert_eq!(contents.lines().count(), 1);
+    }
+}
@@ -27,12 +27,137 @@
 //! - `<PATH_TO_SAVE>`: The path to the file or directory you want to save
 //! - `<STOW_PACKAGE>`: The directory where your Stow packages are stored
 //!
+//! Options:
+//! - `--special-files <abort|exclude|recreate>`: What to do with sockets, FIFOs and device nodes
+//!   inside a saved directory. `abort` (the default) refuses to save the directory, `exclude`
+//!   leaves them in place and saves everything else, and `recreate` additionally recreates FIFOs
+//!   in the package.
+//! - `--exclude <GLOB>`: Leave entries of a saved directory that match `<GLOB>` where they are,
+//!   for example `--exclude Cache --exclude '*.log'`. Globs match the path relative to the saved
+//!   directory as well as the file name. Can be given more than once.
+//! - `--include <GLOB>`: Only save the entries of a saved directory that match `<GLOB>`, for
+//!   example `--include User`. Can be given more than once.
+//!
+//!   When entries are left behind, the saved directory stays a real directory containing them,
+//!   and `stow` is run with `--no-folding` so that it links the saved files one by one instead of
+//!   replacing directories with links into the package.
+//!
+//!   Before saving a directory, stowsave warns about entries that cause trouble in a dotfiles
+//!   repository: nested git repositories, SQLite databases that are in use, lock files, PID
+//!   files, sockets and editor swap files. It lists them by category, followed by the
+//!   `--exclude` options that would leave them behind, even if a later check refuses the save.
+//! - `--follow`: If `<PATH_TO_SAVE>` is a symlink (say `~/.bashrc -> ~/old-dotfiles/bashrc`),
+//!   save a copy of what it points to in its place. Add `--remove-target` to move the target
+//!   into the package instead of copying it.
+//! - `--as-link`: If `<PATH_TO_SAVE>` is a symlink, store the symlink itself in the package.
+//! - `--force`: Save a path even though it is on the deny-list. By default the deny-list contains
+//!   `~/.cache`, `~/.local/share/Trash`, `/proc`, `/sys`, `/dev` and `/run`. Independently of
+//!   the deny-list, stowsave never saves `/`, your home directory, the stow directory, anything
+//!   inside the stow directory or any directory containing it.
+//! - `--config <PATH>`: Read configuration from `<PATH>` instead of
+//!   `~/.config/stowsave/config.toml`. Set `deny = ["~/.cache", ...]` there to replace the
+//!   deny-list.
+//! - `--no-folding`: Run `stow` with `--no-folding`, so that directories in the package become
+//!   real directories of per-file links and files that applications later create there stay out
+//!   of your repository. Set `no_folding = true` in a `[packages.<name>]` table of the config
+//!   file to always do this for a package.
+//! - `--stow-arg <ARG>`: Pass `--ignore=<REGEX>`, `--defer=<REGEX>`, `--override=<REGEX>`,
+//!   `--adopt`, `-v`, `--no-folding` or `--dotfiles` on to `stow`. Can be given more than once.
+//!   With `--dotfiles`, `~/.vimrc` is saved as `dot-vimrc` in the package. The same options
+//!   can be set for all packages in a `[stow]` table of the config file, or for one package in a
+//!   `[packages.<name>]` table.
+//! - `--stow-bin <PATH>`: The `stow` executable to run, for example one installed outside of
+//!   `$PATH`. Defaults to `$STOWSAVE_STOW`, or `stow`. stowsave checks its version first and
+//!   refuses to work with stow older than 2.1.0. Features the installed stow lacks, like
+//!   `--dotfiles` before stow 2.3.0, are switched off with a warning.
+//! - `--allow-hardlinks`: Save files that have other hard links. The other names keep pointing
+//!   at the old contents, so stowsave otherwise asks before (or, when not run interactively,
+//!   refuses) saving them.
+//! - `--allow-secrets`: Save files that look like they contain secrets. Before saving, stowsave
+//!   looks for private keys, AWS, GCP and GitHub tokens, `password=` lines and long random
+//!   strings other than SSH public keys, and for well-known secret files like `~/.ssh/id_*` and
+//!   `~/.netrc`, and asks before (or refuses) saving them. False positives can be listed as globs, one per line, in a
+//!   `.stowsave-allow-secrets` file in the package, which is not linked into the target.
+//! - `--allow-large`: Save a path that is larger than the limits, 10 MB or 1000 files unless
+//!   the `[limits]` table of the config file says otherwise. Past them, stowsave lists the
+//!   largest files and directories, which are candidates for `--exclude`, and asks before (or
+//!   refuses) saving.
+//! - `--dry-run`: Check everything, try the steps out on an in-memory copy of the files they
+//!   touch, and print what would be done, one line per step, without changing anything. A step
+//!   that would fail fails the dry run too. Stow isn't run, so what it would link isn't
+//!   checked. Also works with `unsave` and `unfold`.
+//! - `-v`, `-vv`, `-q`: Show each step as it starts and finishes, with how long it took, and
+//!   the output of `stow` (`-v`), additionally show details such as the stow version found
+//!   (`-vv`), or show nothing but errors (`-q`). Messages go to stderr, so stdout only carries
+//!   output meant for scripts, like the plan printed by `--dry-run`.
+//! - `--log-file <PATH>`: Also append messages to `<PATH>`, with timestamps and always including
+//!   when each step started and finished.
+//! - `--wait`, `--no-wait`: Only one stowsave at a time saves into, unsaves from or unfolds
+//!   into a stow directory. It holds a lock in `~/.local/state/stowsave/locks` from planning
+//!   until the last step has run. With `--no-wait`, the default, another stowsave fails right
+//!   away and names the PID of the one holding the lock. With `--wait`, it waits for the lock
+//!   instead.
+//! - `--output json`: Print one line of JSON on stdout describing what was done, for scripts.
+//!   It has the `original_path`, the `package_path` it was moved to, the `backup_path`, the
+//!   `stow_package`, the `links_created`, the `steps` of the plan, `notes`, `warnings`, and
+//!   `errors` with a stable `code` such as `path_does_not_exist` or `stow_conflicts` next to the
+//!   message. Works for saving and for `unsave`, `unfold`, `status` and `doctor`. The
+//!   `operation` field tells which one ran. `doctor` has no `original_path`, lists the checks
+//!   that passed in `notes` and the ones that failed in `errors`.
+//!
 //! What does the above do?
 //! - Creates a backup of the given `<PATH_TO_SAVE>` file or directory, backing up to
-//! `<PATH_TO_SAVE>.bak`. For directories, the backup is recursive is a recursive copy operation.
+//!   `<PATH_TO_SAVE>.bak`. For directories, the backup is recursive is a recursive copy operation.
 //! - Move the original `<PATH_TO_SAVE>` to the given `<STOW_PACKAGE>`.
 //! - Run `stow` to create symlinks from the `<STOW_PACKAGE>` to the original location of
-//! `<PATH_TO_SAVE>`.
+//!   `<PATH_TO_SAVE>`.
+//!
+//! stowsave reads `~/.stowrc` and the `.stowrc` in the stow directory like `stow` does, so a
+//! `--target` set there decides where in the package a saved path goes. If `<STOW_PACKAGE>` is a
+//! bare name such as `vim` that doesn't exist in the current directory, it is looked up in the
+//! `--dir` of `~/.stowrc` or in `$STOW_DIR`. Paths that `stow` would ignore, like `.gitignore`
+//! with the default ignore list, are refused because they would never be linked back.
+//!
+//! If `<PATH_TO_SAVE>` is inside a directory that stow has folded into a single symlink to another
+//! package, that directory is first unfolded into a real directory of per-entry links, and
+//! `<PATH_TO_SAVE>` is taken out of the other package before being saved.
+//!
+//! To unfold a directory that stow has already folded into a single symlink, such as
+//! `~/.config/nvim -> ../dotfiles/nvim/.config/nvim`, run
+//! ```
+//! stowsave unfold ~/.config/nvim
+//! ```
+//! This replaces the symlink, and the symlinks to any directories below it, with real directories
+//! that contain one link per file. Only links into a stow package are unfolded: the stow directory
+//! is the `--dir` of `~/.stowrc` or `$STOW_DIR`, or else the one that mirrors the link's location
+//! the way stow does, with the target directory as its parent.
+//!
+//! To undo a save, run
+//! ```
+//! stowsave unsave ~/.vimrc
+//! ```
+//! This removes the link `~/.vimrc`, moves the file it links to out of the package into its
+//! place, and removes the directories of the package that only held it. The backup made when
+//! saving stays where it is. `stowsave status ~/.vimrc` shows where a path is saved, if it is.
+//!
+//! `stowsave doctor` checks the config file, the installed stow and the stow directory of
+//! `~/.stowrc` or `$STOW_DIR`, and reports each problem it finds.
+//!
+//! If a step fails, for example because `stow` finds a conflict, the steps before it are undone
+//! so that the file is back where it was. The exit status tells what happened:
+//!
+//! | Status | Meaning |
+//! |--------|---------|
+//! | 0 | Success |
+//! | 1 | Any other error. Nothing was changed |
+//! | 2 | Invalid command line. Nothing was changed |
+//! | 3 | The path can't be saved as asked, for example because it is on the deny-list. Nothing was changed |
+//! | 4 | Something already exists where the path would go in the package, or where its backup would go. Nothing was changed |
+//! | 5 | Reading the filesystem failed. Nothing was changed |
+//! | 6 | `stow` can't be run or is too old. Nothing was changed |
+//! | 7 | A step failed and the steps before it were undone. Nothing was changed |
+//! | 8 | A step failed and undoing the steps before it failed too, or the failed step may have been partly carried out. The error lists the steps that are still in effect, which are also written to a file in `~/.local/state/stowsave/journal` |
+//! | 9 | Another stowsave is saving into the same stow directory. Nothing was changed |
 //!
 //! ## Example
 //! ```
@@ -43,6 +168,15 @@
 //! 2. Rename the original `~/.vimrc` to `~/.vimrc.bak`
 //! 3. Run `stow vim` in the `~/dotfiles` directory
 //!
+//! ## Library
+//!
+//! The planner behind the command line tool is available as the `stowsave` library crate.
+//! `stowsave::plan_save` checks a path and returns the list of commands that save it, which can
+//! be inspected before being executed. `plan_save_on` and `Plan::execute_on` do the same against
+//! any `stowsave::Filesystem`, such as the in-memory `MemoryFs`, which records the stow runs
+//! instead of making them. `Plan::simulate` executes a plan on an in-memory copy of the files it
+//! touches.
+//!
 //! ## Requirements
 //!
 //! - Rust (for building)
@@ -54,117 +188,452 @@
 //!   - [ ] end-to-end tests
 //!   - [ ] create DSL for setup and and verification of directory structures with nested files and symlinks
 //! - [ ] add command line flags such as --no-backup
-//! - [ ] add a command to undo stowsave. This should reverse the `stowsave` operation:
-//!         - remove the symlink that points into the stow package. This could possibly be done by
-//!         running `stow -D` on the stow package, but that would remove all symlinks into the
-//!         package so we'd have to run `stow` again on the package later to restore the other
-//!         symlinks.
-//!         - move the original file out of the stow package and to the location where the symlink
-//!         was.
-//!         - remove empty directorie(s) within the stow package that contained the file that's
-//!         been moved.
+//! - [x] add a command to undo stowsave (`stowsave unsave`)
 //! - [ ] ask for confirmation if it seems that the user is going to stow a directory that contains
-//! symlinks that point into the current or another stow package. For example, running
+//!   symlinks that point into the current or another stow package. For example, running
 //! ```
 //! stowsave .config ~/dotfiles/pkg
 //! ```
 //! might be unwanted if the `.config` folder already contains some symlinks that point into the
 
+mod logger;
+
+use std::io::{self, BufRead, IsTerminal, Write};
 use std::path::{Path, PathBuf};
+use std::process::ExitCode;
 
 use anyhow::{Context, Result};
 use clap::Parser;
-use command::{Command, CommandImpl};
-use util::find_common_ancestor;
+use log::LevelFilter;
+use stowsave::config::Config;
+use stowsave::copy::SpecialFiles;
+use stowsave::doctor;
+use stowsave::lock::StowLock;
+use stowsave::report::{Operation, Report};
+use stowsave::{
+    find_folded_stow_dir, find_saved_path, find_saved_stow_dir, find_stow_dir, plan_save,
+    plan_unfold, plan_unsave, Error, Plan, RealFs, SaveOptions, StowSaveError, SymlinkMode,
+};
 
-mod checks;
-mod command;
-mod util;
+use crate::logger::Logger;
 
 #[derive(Parser, Debug)]
-#[command(author, version, about, long_about = None)]
+#[command(
+    author,
+    version,
+    about,
+    long_about = None,
+    args_conflicts_with_subcommands = true,
+    subcommand_negates_reqs = true
+)]
 struct Args {
+    #[command(subcommand)]
+    action: Option<Action>,
+
     /// Path to the file or directory to save
-    path_to_save: PathBuf,
+    #[arg(required = true)]
+    path_to_save: Option<PathBuf>,
 
     /// The stow package where the file or directory will be saved
-    stow_package: PathBuf,
+    #[arg(required = true)]
+    stow_package: Option<PathBuf>,
 
-    /// Enable verbose output
-    #[arg(short, long)]
-    verbose: bool,
-}
+    /// What to do with sockets, FIFOs and device nodes inside a saved directory
+    #[arg(long, value_enum, default_value_t)]
+    special_files: SpecialFiles,
 
-fn main() -> Result<()> {
-    let args = Args::parse();
+    /// Leave entries matching this glob where they are (can be given more than once)
+    #[arg(long, value_name = "GLOB", conflicts_with_all = ["follow", "as_link"])]
+    exclude: Vec<String>,
 
-    let commands = collect_commands(&args)?;
+    /// Only save entries matching this glob (can be given more than once)
+    #[arg(long, value_name = "GLOB", conflicts_with_all = ["follow", "as_link"])]
+    include: Vec<String>,
 
-    execute_commands(commands, args.verbose)?;
+    /// If the path to save is a symlink, save the file or directory it points to
+    #[arg(long, conflicts_with = "as_link")]
+    follow: bool,
 
-    // TODO:
-    // checks::check_that_symlink_has_been_created(&args.path_to_save, &args.stow_package)?;
+    /// With --follow, move the symlink's target into the package instead of copying it
+    #[arg(long, requires = "follow")]
+    remove_target: bool,
 
-    println!("Path successfully saved, backed up, and stowed");
-    Ok(())
+    /// If the path to save is a symlink, save the symlink itself
+    #[arg(long)]
+    as_link: bool,
+
+    /// Save files even if they have other hard links, which will keep the old contents
+    #[arg(long)]
+    allow_hardlinks: bool,
+
+    /// Save files even if they look like they contain secrets, such as private keys or tokens
+    #[arg(long)]
+    allow_secrets: bool,
+
+    /// Save the path even if it is larger than the limits of the config file
+    #[arg(long)]
+    allow_large: bool,
+
+    /// Save the path even if it is on the deny-list
+    #[arg(long)]
+    force: bool,
+
+    /// Run stow with --no-folding, so that it links files one by one instead of linking
+    /// whole directories
+    #[arg(long)]
+    no_folding: bool,
+
+    /// Path to the config file [default: ~/.config/stowsave/config.toml]
+    #[arg(long, global = true)]
+    config: Option<PathBuf>,
+
+    /// The stow executable to run
+    #[arg(
+        long,
+        value_name = "PATH",
+        env = "STOWSAVE_STOW",
+        default_value = "stow",
+        global = true
+    )]
+    stow_bin: PathBuf,
+
+    /// Pass an option such as --ignore=REGEX, --defer=REGEX, --override=REGEX, --adopt, -v,
+    /// --no-folding or --dotfiles on to stow (can be given more than once)
+    #[arg(long, value_name = "ARG", allow_hyphen_values = true)]
+    stow_arg: Vec<String>,
+
+    /// Show more of what is going on, -vv for even more
+    #[arg(short, long, action = clap::ArgAction::Count, global = true)]
+    verbose: u8,
+
+    /// Only show errors
+    #[arg(short, long, global = true, conflicts_with = "verbose")]
+    quiet: bool,
+
+    /// Also append log messages, down to debug level, to this file
+    #[arg(long, value_name = "PATH", global = true)]
+    log_file: Option<PathBuf>,
+
+    /// Print what would be done instead of doing it
+    #[arg(long, global = true)]
+    dry_run: bool,
+
+    /// If another stowsave is working on the same stow directory, wait for it to finish
+    #[arg(long, global = true, overrides_with = "no_wait")]
+    wait: bool,
+
+    /// If another stowsave is working on the same stow directory, fail right away (the default)
+    #[arg(long, global = true, overrides_with = "wait")]
+    no_wait: bool,
+
+    /// Print human-readable messages, or one JSON document per operation on stdout
+    #[arg(long, value_enum, default_value_t, global = true)]
+    output: OutputFormat,
+}
+
+#[derive(clap::ValueEnum, Debug, Clone, Copy, Default, PartialEq, Eq)]
+enum OutputFormat {
+    #[default]
+    Text,
+    Json,
 }
 
-fn backup_path_command(original: &Path) -> Command {
-    let backup_name: String = original.file_name().unwrap().to_str().unwrap().to_string() + ".bak";
-    Command::CreateBackup {
-        original: original.to_owned(),
-        backup_name,
+#[derive(clap::Subcommand, Debug)]
+enum Action {
+    /// Move a saved file or directory out of its stow package, back to where the link to it is
+    Unsave {
+        /// The link into the package, such as ~/.vimrc
+        path: PathBuf,
+    },
+    /// Replace a directory symlink created by stow with a real directory of per-file links
+    Unfold {
+        /// The folded directory, such as ~/.config/nvim
+        dir: PathBuf,
+    },
+    /// Tell whether a path is saved, and where
+    Status {
+        /// The path to look at, such as ~/.vimrc
+        path: PathBuf,
+    },
+    /// Check that stow runs and that the config file and the stow directory are in order
+    Doctor,
+}
+
+impl Args {
+    fn log_level(&self) -> LevelFilter {
+        match (self.quiet, self.verbose) {
+            (true, _) => LevelFilter::Error,
+            (false, 0) => LevelFilter::Info,
+            (false, 1) => LevelFilter::Debug,
+            (false, _) => LevelFilter::Trace,
+        }
+    }
+
+    fn report(&self) -> Report {
+        let (operation, path) = match (&self.action, &self.path_to_save) {
+            (Some(Action::Unsave { path }), _) => (Operation::Unsave, Some(path)),
+            (Some(Action::Unfold { dir }), _) => (Operation::Unfold, Some(dir)),
+            (Some(Action::Status { path }), _) => (Operation::Status, Some(path)),
+            (Some(Action::Doctor), _) => (Operation::Doctor, None),
+            (None, Some(path_to_save)) => (Operation::Save, Some(path_to_save)),
+            (None, None) => {
+                unreachable!("clap requires the path to save unless a subcommand is given")
+            }
+        };
+        Report::new(operation, path.map(PathBuf::as_path), self.dry_run)
+    }
+
+    fn symlink_mode(&self) -> SymlinkMode {
+        if self.follow {
+            SymlinkMode::Follow {
+                remove_target: self.remove_target,
+            }
+        } else if self.as_link {
+            SymlinkMode::AsLink
+        } else {
+            SymlinkMode::Refuse
+        }
+    }
+
+    fn save_options(&self, config: Config) -> SaveOptions {
+        let mut options = SaveOptions::new(config);
+        options.special_files = self.special_files;
+        options.exclude = self.exclude.clone();
+        options.include = self.include.clone();
+        options.symlink_mode = self.symlink_mode();
+        options.allow_hardlinks = self.allow_hardlinks;
+        options.allow_secrets = self.allow_secrets;
+        options.allow_large = self.allow_large;
+        options.force = self.force;
+        options.no_folding = self.no_folding;
+        options.stow_bin = self.stow_bin.clone();
+        options.stow_args = self.stow_arg.clone();
+        options
     }
 }
 
-fn collect_commands(args: &Args) -> Result<Vec<Command>> {
-    let mut commands = Vec::new();
+fn main() -> ExitCode {
+    let args = Args::parse();
+    let logger = match Logger::new(args.log_level(), args.log_file.as_deref()) {
+        Ok(logger) => logger,
+        Err(err) => {
+            eprintln!("error: Failed to open the log file: {}", err);
+            return ExitCode::FAILURE;
+        }
+    };
+    logger.init().expect("no other logger is installed");
 
-    let path_to_save = args
-        .path_to_save
-        .canonicalize()
-        .context("Failed to canonicalize path_to_save")?;
+    let mut report = args.report();
+    let result = run(&args, &mut report).map_err(Error::from);
+    if let Err(err) = &result {
+        log::error!("{:#}", err);
+        report.record_error(err);
+    }
+    if args.output == OutputFormat::Json {
+        println!("{}", report.to_json());
+    }
+    match result {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(err) => ExitCode::from(err.exit_code()),
+    }
+}
 
-    commands.push(backup_path_command(&path_to_save));
+fn run(args: &Args, report: &mut Report) -> Result<()> {
+    match &args.action {
+        Some(Action::Unsave { path }) => return unsave(args, report, path),
+        Some(Action::Unfold { dir }) => return unfold(args, report, dir),
+        Some(Action::Status { path }) => return status(args, report, path),
+        Some(Action::Doctor) => return doctor(args, report),
+        None => {}
+    }
+    let (Some(path_to_save), Some(stow_package)) = (&args.path_to_save, &args.stow_package) else {
+        unreachable!("clap requires both paths unless a subcommand is given");
+    };
 
-    let stow_pkg = args
-        .stow_package
-        .canonicalize()
-        .context("Failed to canonicalize stow_package")?;
+    let config = Config::load(args.config.as_deref())?;
+    let mut options = args.save_options(config);
+    // Hold the lock from planning until stow has run, so that what the checks saw still holds
+    // when the plan is executed.
+    let _lock = if args.dry_run {
+        None
+    } else {
+        Some(StowLock::acquire(&find_stow_dir(stow_package)?, args.wait)?)
+    };
 
-    checks::path_to_save_exists(&path_to_save)?;
-    checks::path_to_save_is_not_symlink(&path_to_save)?;
-    checks::stow_directory_exists(&stow_pkg)?;
+    // Each check the user can override ends the planning with an error. Ask about it and plan
+    // again, until the plan goes through or the user declines.
+    let plan = loop {
+        let err = match plan_save(path_to_save, stow_package, &options) {
+            Ok(plan) => break plan,
+            Err(err) => err,
+        };
+        let (warning, question, allow) = match err.downcast_ref::<StowSaveError>() {
+            Some(StowSaveError::ContainsHardlinks(_, hardlinks)) => (
+                format!(
+                    "These files have other hard links that will keep their old contents:\n{}",
+                    hardlinks
+                ),
+                "Save them anyway?",
+                &mut options.allow_hardlinks,
+            ),
+            Some(StowSaveError::ContainsSecrets(_, findings)) => (
+                format!("These files look like they contain secrets:\n{}", findings),
+                "Save them into the package anyway?",
+                &mut options.allow_secrets,
+            ),
+            Some(StowSaveError::TooLarge(_, limits, usage)) => (
+                format!("This is larger than the limits of {}:\n{}", limits, usage),
+                "Save all of it anyway?",
+                &mut options.allow_large,
+            ),
+            _ => return Err(err.into()),
+        };
+        log::warn!("{}", warning);
+        // A prompt would end up in the middle of the JSON on stdout.
+        if args.output == OutputFormat::Json || !confirm(question)? {
+            return Err(err.into());
+        }
+        *allow = true;
+    };
+    report.record_plan(&plan);
+    for warning in &plan.warnings {
+        log::warn!("{}", warning);
+    }
+    for note in &plan.notes {
+        log::info!("{}", note);
+    }
+    if args.dry_run {
+        return dry_run(args, &plan);
+    }
 
-    let common_ancestor = find_common_ancestor(&path_to_save, &stow_pkg);
-    checks::stow_directory_is_grandchild_of_common_ancestor(&stow_pkg, &common_ancestor)?;
+    plan.execute(&mut ())?;
+    report
+        .record_links(&RealFs)
+        .context("Failed to look for the links created")?;
+
+    // TODO:
+    // checks::check_that_symlink_has_been_created(path_to_save, stow_package)?;
 
-    // Compute the relative path from the common ancestor to the path to save
-    let relative_path_from_ancestor_to_path_to_save =
-        path_to_save.strip_prefix(&common_ancestor)?;
+    log::info!("Path successfully saved, backed up, and stowed");
+    Ok(())
+}
 
-    let target_path = stow_pkg.join(relative_path_from_ancestor_to_path_to_save);
-    checks::target_path_does_not_exist(&target_path)?;
-    let target_dir = target_path.parent().unwrap().to_owned();
-    commands.push(Command::CreateDirIfNotExists(target_dir.clone()));
+fn unsave(args: &Args, report: &mut Report, path: &Path) -> Result<()> {
+    let _lock = if args.dry_run {
+        None
+    } else {
+        Some(StowLock::acquire(&find_saved_stow_dir(path)?, args.wait)?)
+    };
+    let plan = plan_unsave(path)?;
+    report.record_plan(&plan);
+    for note in &plan.notes {
+        log::info!("{}", note);
+    }
+    if args.dry_run {
+        return dry_run(args, &plan);
+    }
+    plan.execute(&mut ())?;
+    log::info!("Path successfully moved out of the stow package");
+    Ok(())
+}
 
-    commands.push(Command::MoveToDir {
-        from: path_to_save.clone(),
-        dest_dir: target_dir,
-    });
+fn unfold(args: &Args, report: &mut Report, dir: &Path) -> Result<()> {
+    let _lock = if args.dry_run {
+        None
+    } else {
+        Some(StowLock::acquire(&find_folded_stow_dir(dir)?, args.wait)?)
+    };
+    let plan = plan_unfold(dir)?;
+    report.record_plan(&plan);
+    if args.dry_run {
+        return dry_run(args, &plan);
+    }
+    plan.execute(&mut ())?;
+    report
+        .record_links(&RealFs)
+        .context("Failed to look for the links created")?;
+    log::info!("Directory successfully unfolded");
+    Ok(())
+}
 
-    let stow_package = stow_pkg.file_name().unwrap().to_str().unwrap().to_string();
-    commands.push(Command::RunStow {
-        pwd: stow_pkg.parent().unwrap().to_owned(),
-        package: stow_package,
-    });
+fn status(args: &Args, report: &mut Report, path: &Path) -> Result<()> {
+    let Some(saved) = find_saved_path(path)? else {
+        let note = format!("'{}' is not saved in a stow package", path.display());
+        if args.output == OutputFormat::Text {
+            println!("{}", note);
+        }
+        report.notes.push(note);
+        return Ok(());
+    };
+    report.record_saved(&saved);
+    report
+        .record_links(&RealFs)
+        .context("Failed to look for the links into the package")?;
+    if args.output == OutputFormat::Text {
+        println!(
+            "'{}' is saved as '{}' in package '{}'",
+            saved.original.display(),
+            saved.package_path.display(),
+            saved.package.display()
+        );
+        if let Some(backup) = &saved.backup {
+            println!("Its backup is '{}'", backup.display());
+        }
+    }
+    Ok(())
+}
 
-    Ok(commands)
+fn doctor(args: &Args, report: &mut Report) -> Result<()> {
+    let mut failures = Vec::new();
+    for check in doctor::diagnose(&RealFs, args.config.as_deref(), &args.stow_bin) {
+        match check.result {
+            Ok(found) => {
+                log::info!("{}: {}", check.name, found);
+                report.notes.push(format!("{}: {}", check.name, found));
+            }
+            Err(err) => failures.push(err),
+        }
+    }
+    // The last failure is returned and reported like the error of any other operation, which
+    // keeps the errors in the order of the checks.
+    let Some(last) = failures.pop() else {
+        log::info!("Everything is in order");
+        return Ok(());
+    };
+    for err in &failures {
+        log::error!("{:#}", err);
+        report.record_error(err);
+    }
+    Err(last.into())
 }
 
-fn execute_commands(commands: Vec<Command>, verbose: bool) -> Result<()> {
-    for command in commands {
-        command.invoke(verbose)?;
+/// Try the plan out in memory, and print it unless it goes into the JSON report instead.
+fn dry_run(args: &Args, plan: &Plan) -> Result<()> {
+    if let Err(err) = plan.simulate() {
+        log::info!("Dry run, nothing was changed. The plan failed on a copy of the files");
+        return Err(err.into());
+    }
+    if args.output == OutputFormat::Json {
+        return Ok(());
+    }
+    println!(
+        "Dry run, nothing was changed. The plan, which went through on a copy of the files, is:"
+    );
+    for command in &plan.commands {
+        println!("  {}", command.describe());
     }
     Ok(())
 }
+
+/// Ask the user a yes/no question on the terminal. Answers "no" when stdin isn't a terminal, so
+/// scripts never block on a prompt.
+fn confirm(question: &str) -> io::Result<bool> {
+    if !io::stdin().is_terminal() {
+        return Ok(false);
+    }
+    print!("{} [y/N] ", question);
+    io::stdout().flush()?;
+    let mut answer = String::new();
+    io::stdin().lock().read_line(&mut answer)?;
+    Ok(matches!(answer.trim(), "y" | "Y" | "yes" | "Yes"))
+}
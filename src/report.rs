@@ -0,0 +1,269 @@
+//! Machine-readable reports of what an operation did, as printed by `--output json`.
+//!
+//! Each operation, `save`, `unsave`, `unfold`, `status` or `doctor`, produces one [`Report`],
+//! serialized as a single line of JSON:
+//!
+//! ```json
+//! {"operation":"save","success":true,"dry_run":false,
+//!  "original_path":"/home/user/.vimrc","package_path":"/home/user/dotfiles/vim/.vimrc",
+//!  "backup_path":"/home/user/.vimrc.bak","stow_package":"/home/user/dotfiles/vim",
+//!  "links_created":["/home/user/.vimrc"],"steps":["..."],"notes":[],"warnings":[],"problems":[],
+//!  "errors":[]}
+//! ```
+//!
+//! For `unsave`, the paths are where the saved path was in the package and where it goes back
+//! to. For `status`, they say where the path is saved, and `links_created` lists the links into
+//! the package that saving left. `doctor` isn't about a path, so its `original_path` is `null`;
+//! each check that passed is one of its `notes`, and each that failed one of its `errors`.
+//!
+//! Each of the `problems` found in a saved directory has a `category`, such as
+//! `nested_repository` or `swap_file`, the `path` relative to the directory and the `exclude`
+//! glob that leaves it behind, if there is one.
+//!
+//! Errors carry a stable `code`, such as `path_does_not_exist` or `stow_conflicts`, that scripts
+//! can match on instead of the message.
+use std::io;
+use std::path::{Path, PathBuf};
+
+use serde::Serialize;
+
+use crate::audit::Problem;
+use crate::command::Command;
+use crate::error::Error;
+use crate::filesystem::Filesystem;
+use crate::plan::{Plan, SavedPath};
+
+/// The kind of operation a [`Report`] is about.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Operation {
+    Save,
+    Unsave,
+    Unfold,
+    Status,
+    Doctor,
+}
+
+/// What an operation did, or would have done in a dry run.
+#[derive(Debug, Serialize)]
+pub struct Report {
+    pub operation: Operation,
+    pub success: bool,
+    pub dry_run: bool,
+    /// The path that was saved, unsaved, unfolded or looked at.
+    pub original_path: Option<String>,
+    /// Where the saved file or directory ended up inside the package.
+    pub package_path: Option<String>,
+    pub backup_path: Option<String>,
+    /// The stow package directory.
+    pub stow_package: Option<String>,
+    /// The symlinks that point into the package after the operation.
+    pub links_created: Vec<String>,
+    /// The description of each command of the plan.
+    pub steps: Vec<String>,
+    pub notes: Vec<String>,
+    pub warnings: Vec<String>,
+    /// Entries of a saved directory that are likely to cause trouble in the package.
+    pub problems: Vec<Problem>,
+    pub errors: Vec<ReportError>,
+    /// Where to look for links once the plan has run, and the directory they point into.
+    #[serde(skip)]
+    links: Option<(PathBuf, Option<PathBuf>)>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct ReportError {
+    pub code: &'static str,
+    pub message: String,
+}
+
+impl Report {
+    /// An empty, successful report about `original_path`, as given by the user.
+    pub fn new(operation: Operation, original_path: Option<&Path>, dry_run: bool) -> Report {
+        Report {
+            operation,
+            success: true,
+            dry_run,
+            original_path: original_path.map(|path| path.display().to_string()),
+            package_path: None,
+            backup_path: None,
+            stow_package: None,
+            links_created: Vec::new(),
+            steps: Vec::new(),
+            notes: Vec::new(),
+            warnings: Vec::new(),
+            problems: Vec::new(),
+            errors: Vec::new(),
+            links: None,
+        }
+    }
+
+    /// Take the paths, steps, notes, warnings and problems from `plan`.
+    pub fn record_plan(&mut self, plan: &Plan) {
+        self.steps = plan.commands.iter().map(Command::describe).collect();
+        self.notes = plan.notes.clone();
+        self.warnings = plan.warnings.clone();
+        self.problems = plan.problems.clone();
+        if let Some(saved) = &plan.saved {
+            self.record_saved(saved);
+        } else if let Some(Command::UnfoldDir(dir)) = plan.commands.first() {
+            // Unfolding replaces one link with many, so every link below `dir` is new.
+            self.original_path = Some(dir.display().to_string());
+            self.links = Some((dir.clone(), None));
+        }
+    }
+
+    /// Take the paths from `saved`, and look for links into its package in
+    /// [`Report::record_links`].
+    pub fn record_saved(&mut self, saved: &SavedPath) {
+        self.original_path = Some(saved.original.display().to_string());
+        self.package_path = Some(saved.package_path.display().to_string());
+        self.backup_path = saved.backup.as_ref().map(|path| path.display().to_string());
+        self.stow_package = Some(saved.package.display().to_string());
+        self.links = Some((saved.original.clone(), Some(saved.package.clone())));
+    }
+
+    /// Find the links created by the plan given to [`Report::record_plan`], once it has run.
+    pub fn record_links(&mut self, fs: &dyn Filesystem) -> io::Result<()> {
+        if let Some((path, package)) = &self.links {
+            self.links_created = find_links(fs, path, package.as_deref())?
+                .iter()
+                .map(|link| link.display().to_string())
+                .collect();
+        }
+        Ok(())
+    }
+
+    /// Mark the operation as failed because of `err`.
+    pub fn record_error(&mut self, err: &Error) {
+        self.success = false;
+        self.errors.push(ReportError {
+            code: err.code(),
+            message: format!("{:#}", err),
+        });
+    }
+
+    pub fn to_json(&self) -> String {
+        serde_json::to_string(self).expect("reports only contain strings and booleans")
+    }
+}
+
+/// Find `path`, or the symlinks below it, if they are symlinks that point into `package`, or
+/// anywhere if no package is given.
+fn find_links(
+    fs: &dyn Filesystem,
+    path: &Path,
+    package: Option<&Path>,
+) -> io::Result<Vec<PathBuf>> {
+    let metadata = match fs.symlink_metadata(path) {
+        Ok(metadata) => metadata,
+        Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(err) => return Err(err),
+    };
+    if metadata.is_symlink() {
+        let points_into_package = match (package, path.parent()) {
+            (Some(package), Some(parent)) => {
+                let target = fs.canonicalize(parent)?.join(fs.read_link(path)?);
+                path_clean::clean(target).starts_with(package)
+            }
+            _ => true,
+        };
+        return Ok(if points_into_package {
+            vec![path.to_owned()]
+        } else {
+            Vec::new()
+        });
+    }
+    let mut links = Vec::new();
+    if metadata.is_dir() {
+        for entry in fs.read_dir(path)? {
+            links.extend(find_links(fs, &entry, package)?);
+        }
+    }
+    Ok(links)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::filesystem::MemoryFs;
+    use crate::plan::{plan_save_on, plan_unfold_on, SaveOptions};
+
+    #[test]
+    fn test_report_of_save() {
+        let fs = MemoryFs::new();
+        let home = Path::new("/home/user");
+        let package = home.join("dotfiles").join("vim");
+        fs.create_dir_all(&package).unwrap();
+        fs.write(&home.join(".vimrc"), "set number").unwrap();
+        let plan =
+            plan_save_on(&fs, &home.join(".vimrc"), &package, &SaveOptions::default()).unwrap();
+        plan.execute_on(&fs, &mut ()).unwrap();
+        // MemoryFs records stow runs instead of making them, so link the file the way stow would.
+        fs.symlink(Path::new("dotfiles/vim/.vimrc"), &home.join(".vimrc"))
+            .unwrap();
+
+        let mut report = Report::new(Operation::Save, Some(Path::new("~/.vimrc")), false);
+        report.record_plan(&plan);
+        report.record_links(&fs).unwrap();
+
+        assert_eq!(report.original_path.as_deref(), Some("/home/user/.vimrc"));
+        assert_eq!(
+            report.package_path.as_deref(),
+            Some("/home/user/dotfiles/vim/.vimrc")
+        );
+        assert_eq!(report.backup_path.as_deref(), Some("/home/user/.vimrc.bak"));
+        assert_eq!(report.links_created, vec!["/home/user/.vimrc"]);
+        assert_eq!(report.steps.len(), plan.commands.len());
+        let json: serde_json::Value = serde_json::from_str(&report.to_json()).unwrap();
+        assert_eq!(json["operation"], "save");
+        assert_eq!(json["stow_package"], "/home/user/dotfiles/vim");
+    }
+
+    #[test]
+    fn test_report_of_unfold() {
+        let fs = MemoryFs::new();
+        let package_dir = Path::new("/home/user/dotfiles/nvim/nvim");
+        fs.create_dir_all(&package_dir.join("lua")).unwrap();
+        fs.write(&package_dir.join("init.lua"), "").unwrap();
+        fs.write(&package_dir.join("lua/plugins.lua"), "").unwrap();
+        fs.symlink(package_dir, Path::new("/home/user/nvim"))
+            .unwrap();
+        let plan = plan_unfold_on(&fs, Path::new("/home/user/nvim")).unwrap();
+        plan.execute_on(&fs, &mut ()).unwrap();
+
+        let mut report = Report::new(Operation::Unfold, Some(Path::new("nvim")), false);
+        report.record_plan(&plan);
+        report.record_links(&fs).unwrap();
+
+        let mut links = report.links_created.clone();
+        links.sort();
+        assert_eq!(
+            links,
+            vec![
+                "/home/user/nvim/init.lua",
+                "/home/user/nvim/lua/plugins.lua"
+            ]
+        );
+    }
+
+    #[test]
+    fn test_report_of_failure() {
+        let fs = MemoryFs::new();
+        fs.create_dir_all(Path::new("/home/user/dotfiles/vim"))
+            .unwrap();
+        let err = plan_save_on(
+            &fs,
+            Path::new("/home/user/.vimrc"),
+            Path::new("/home/user/dotfiles/vim"),
+            &SaveOptions::default(),
+        )
+        .unwrap_err();
+
+        let mut report = Report::new(Operation::Save, Some(Path::new("/home/user/.vimrc")), false);
+        report.record_error(&err);
+
+        assert!(!report.success);
+        assert_eq!(report.errors[0].code, "path_does_not_exist");
+    }
+}
@@ -0,0 +1,281 @@
+//! Choosing which entries of a saved tree go into the stow package.
+//!
+//! Entries can be left out with `--exclude` globs, limited to `--include` globs, or skipped for
+//! being special files (see [`SpecialFiles`]). Whatever isn't selected stays where it is.
+//!
+//! Globs are matched against the path of an entry relative to the saved directory, and against
+//! its file name, so `Cache` excludes every entry called `Cache` while `User/History/**` only
+//! excludes below that directory.
+use std::io;
+use std::path::{Path, PathBuf};
+
+use anyhow::{Context, Result};
+use globset::{GlobBuilder, GlobSet, GlobSetBuilder};
+
+use crate::copy::SpecialFiles;
+use crate::filesystem::{FileType, Filesystem};
+
+/// Which entries of a tree are copied, moved and backed up.
+#[derive(Debug, Clone, Default)]
+pub struct Selection {
+    pub special_files: SpecialFiles,
+    exclude: Patterns,
+    include: Option<Patterns>,
+}
+
+/// How a walk over a tree treats one entry.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Visit {
+    /// Leave the entry, and everything below it, where it is.
+    Skip,
+    /// Take the entry and everything below it that isn't excluded.
+    Take,
+    /// A directory that isn't selected itself but may contain selected entries.
+    Descend,
+}
+
+/// What a [`Selection`] does to a particular tree.
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct Partition {
+    /// The number of selected entries, not counting a saved directory itself.
+    pub selected: usize,
+    /// The outermost entries that are left where they are.
+    pub left_behind: Vec<PathBuf>,
+}
+
+impl Selection {
+    pub fn new(
+        special_files: SpecialFiles,
+        exclude: &[String],
+        include: &[String],
+    ) -> Result<Selection> {
+        Ok(Selection {
+            special_files,
+            exclude: Patterns::new(exclude)?,
+            include: match include {
+                [] => None,
+                include => Some(Patterns::new(include)?),
+            },
+        })
+    }
+
+    /// Decide what to do with the entry at `relative`, given what was decided for its parent.
+    /// The root of a walk has an empty `relative` path and [`Visit::Descend`] as its parent.
+    pub fn visit(&self, relative: &Path, file_type: FileType, parent: Visit) -> Visit {
+        if self.special_files.excludes(file_type) {
+            return Visit::Skip;
+        }
+        self.visit_patterns(relative, file_type, parent)
+    }
+
+    /// Like [`Selection::visit`], but ignoring the special file policy.
+    pub fn visit_patterns(&self, relative: &Path, file_type: FileType, parent: Visit) -> Visit {
+        let is_root = relative.as_os_str().is_empty();
+        if !is_root && self.exclude.matches(relative) {
+            return Visit::Skip;
+        }
+        match &self.include {
+            None => Visit::Take,
+            Some(_) if parent == Visit::Take => Visit::Take,
+            Some(include) if !is_root && include.matches(relative) => Visit::Take,
+            Some(_) if file_type.is_dir() => Visit::Descend,
+            Some(_) => Visit::Skip,
+        }
+    }
+
+    /// Decide what to do with the entry at `relative`, a path below the root of a walk, by
+    /// visiting the directories above it on the way. The entry is of type `file_type`.
+    pub fn visit_path(&self, relative: &Path, file_type: FileType) -> Visit {
+        let mut visit = self.visit(Path::new(""), FileType::Dir, Visit::Descend);
+        let mut path = PathBuf::new();
+        let mut components = relative.iter().peekable();
+        while let Some(component) = components.next() {
+            if visit == Visit::Skip {
+                break;
+            }
+            path.push(component);
+            let is_last = components.peek().is_none();
+            let file_type = if is_last { file_type } else { FileType::Dir };
+            visit = self.visit(&path, file_type, visit);
+        }
+        visit
+    }
+
+    /// Walk the tree at `root` and find out what this selection takes and leaves behind.
+    pub fn partition(&self, fs: &dyn Filesystem, root: &Path) -> io::Result<Partition> {
+        let mut partition = Partition::default();
+        self.partition_entry(fs, root, Path::new(""), Visit::Descend, &mut partition)?;
+        Ok(partition)
+    }
+
+    /// Returns whether anything at or below `path` is selected.
+    fn partition_entry(
+        &self,
+        fs: &dyn Filesystem,
+        path: &Path,
+        relative: &Path,
+        parent: Visit,
+        partition: &mut Partition,
+    ) -> io::Result<bool> {
+        let file_type = fs.symlink_metadata(path)?.file_type;
+        let visit = self.visit(relative, file_type, parent);
+        if visit == Visit::Skip || (visit == Visit::Descend && !file_type.is_dir()) {
+            partition.left_behind.push(path.to_owned());
+            return Ok(false);
+        }
+        // Taking a directory with nothing in it saves nothing.
+        if visit == Visit::Take && !(relative.as_os_str().is_empty() && file_type.is_dir()) {
+            partition.selected += 1;
+        }
+        if !file_type.is_dir() {
+            return Ok(true);
+        }
+        let left_before = partition.left_behind.len();
+        let mut selected_any = false;
+        for entry in fs.read_dir(path)? {
+            let child_relative = relative.join(entry.file_name().unwrap());
+            selected_any |= self.partition_entry(fs, &entry, &child_relative, visit, partition)?;
+        }
+        if visit == Visit::Descend && !selected_any {
+            // Nothing in here is selected, so the whole directory stays.
+            partition.left_behind.truncate(left_before);
+            partition.left_behind.push(path.to_owned());
+            return Ok(false);
+        }
+        Ok(true)
+    }
+}
+
+impl From<SpecialFiles> for Selection {
+    /// Select everything except the special files that `special_files` excludes.
+    fn from(special_files: SpecialFiles) -> Selection {
+        Selection {
+            special_files,
+            ..Selection::default()
+        }
+    }
+}
+
+/// Globs matched against relative paths and file names.
+#[derive(Debug, Clone, Default)]
+pub(crate) struct Patterns {
+    set: GlobSet,
+}
+
+impl Patterns {
+    pub(crate) fn new(globs: &[String]) -> Result<Patterns> {
+        let mut builder = GlobSetBuilder::new();
+        for glob in globs {
+            builder.add(
+                GlobBuilder::new(glob)
+                    .literal_separator(true)
+                    .build()
+                    .with_context(|| format!("Invalid glob '{}'", glob))?,
+            );
+        }
+        Ok(Patterns {
+            set: builder.build()?,
+        })
+    }
+
+    pub(crate) fn matches(&self, relative: &Path) -> bool {
+        self.set.is_match(relative)
+            || relative
+                .file_name()
+                .is_some_and(|name| self.set.is_match(name))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::filesystem::MemoryFs;
+
+    fn selection(exclude: &[&str], include: &[&str]) -> Selection {
+        let to_strings = |globs: &[&str]| globs.iter().map(|glob| glob.to_string()).collect();
+        let exclude: Vec<String> = to_strings(exclude);
+        let include: Vec<String> = to_strings(include);
+        Selection::new(SpecialFiles::Abort, &exclude, &include).unwrap()
+    }
+
+    /// A tree shaped like `~/.config/Code`.
+    fn create_tree(root: &Path) -> MemoryFs {
+        let fs = MemoryFs::new();
+        fs.create_dir_all(&root.join("User").join("snippets"))
+            .unwrap();
+        fs.create_dir_all(&root.join("Cache")).unwrap();
+        fs.create_dir_all(&root.join("CachedData").join("abc"))
+            .unwrap();
+        fs.write(&root.join("User").join("settings.json"), "{}")
+            .unwrap();
+        fs.write(&root.join("User").join("snippets").join("rust.json"), "{}")
+            .unwrap();
+        fs.write(&root.join("Cache").join("data"), "").unwrap();
+        fs.write(&root.join("CachedData").join("abc").join("data"), "")
+            .unwrap();
+        fs.write(&root.join("main.log"), "").unwrap();
+        fs
+    }
+
+    #[test]
+    fn test_partition_everything() {
+        let root = Path::new("/home/user/.config/Code");
+        let fs = create_tree(root);
+
+        let partition = Selection::default().partition(&fs, root).unwrap();
+        assert_eq!(partition.selected, 10);
+        assert!(partition.left_behind.is_empty());
+    }
+
+    #[test]
+    fn test_partition_with_exclude() {
+        let root = Path::new("/home/user/.config/Code");
+        let fs = create_tree(root);
+
+        let mut partition = selection(&["Cache", "CachedData", "*.log"], &[])
+            .partition(&fs, root)
+            .unwrap();
+        partition.left_behind.sort();
+        assert_eq!(
+            partition.left_behind,
+            vec![
+                root.join("Cache"),
+                root.join("CachedData"),
+                root.join("main.log")
+            ]
+        );
+        assert_eq!(partition.selected, 4);
+    }
+
+    #[test]
+    fn test_partition_with_include() {
+        let root = Path::new("/home/user/.config/Code");
+        let fs = create_tree(root);
+
+        let mut partition = selection(&["User/snippets"], &["User"])
+            .partition(&fs, root)
+            .unwrap();
+        partition.left_behind.sort();
+        assert_eq!(
+            partition.left_behind,
+            vec![
+                root.join("Cache"),
+                root.join("CachedData"),
+                root.join("User").join("snippets"),
+                root.join("main.log"),
+            ]
+        );
+        // `User` and `User/settings.json`
+        assert_eq!(partition.selected, 2);
+        let selection = selection(&["User/snippets"], &["User"]);
+        let visit = |path: &str| selection.visit_path(Path::new(path), FileType::File);
+        assert_eq!(visit("User/settings.json"), Visit::Take);
+        assert_eq!(visit("User/snippets/rust.json"), Visit::Skip);
+        assert_eq!(visit("main.log"), Visit::Skip);
+    }
+
+    #[test]
+    fn test_invalid_glob() {
+        assert!(Selection::new(SpecialFiles::Abort, &["a[".to_string()], &[]).is_err());
+    }
+}
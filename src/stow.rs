@@ -0,0 +1,843 @@
+//! Invoking GNU stow and interpreting its output.
+//!
+//! Besides what stowsave passes on the command line, stow reads options from `~/.stowrc` and
+//! from `.stowrc` in the directory it runs in, and skips files that match its ignore lists. We
+//! read the same files so that our idea of the target directory and of ignored files agrees with
+//! stow's.
+//!
+//! Stow reports problems as human-oriented Perl warnings on stderr. This module turns the
+//! messages we know about into [`StowError`] values so that callers can match on them and show
+//! the user a concrete way forward.
+use std::ffi::{OsStr, OsString};
+use std::fmt;
+use std::os::unix::ffi::OsStrExt;
+use std::path::{Path, PathBuf};
+use std::sync::LazyLock;
+
+use anyhow::{anyhow, Context, Result};
+use regex::Regex;
+use thiserror::Error;
+
+use crate::filesystem::Filesystem;
+use crate::util;
+
+/// A single conflict that stow refused to resolve on its own.
+///
+/// Paths are reported exactly as stow prints them, which is relative to the stow target
+/// directory.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum StowConflict {
+    /// A regular file (or other non-link, non-directory) is in the way.
+    NeitherLinkNorDirectory { target: PathBuf },
+    /// A symlink is in the way, but it does not point into the stow directory.
+    NotOwnedByStow { target: PathBuf },
+    /// The target is already a stow link belonging to another package.
+    StowedToDifferentPackage { target: PathBuf, source: PathBuf },
+    /// Stow would have to replace `target` with a link to `source`.
+    CannotStowOverExisting {
+        source: PathBuf,
+        target: PathBuf,
+        reason: String,
+    },
+    /// A conflict message we don't know how to parse.
+    Unrecognized(String),
+}
+
+impl StowConflict {
+    /// Parse one `* ...` line from stow's conflict report.
+    fn parse(message: &str) -> StowConflict {
+        if let Some(target) =
+            message.strip_prefix("existing target is neither a link nor a directory: ")
+        {
+            return StowConflict::NeitherLinkNorDirectory {
+                target: target.into(),
+            };
+        }
+        if let Some(target) = message.strip_prefix("existing target is not owned by stow: ") {
+            return StowConflict::NotOwnedByStow {
+                target: target.into(),
+            };
+        }
+        if let Some(rest) =
+            message.strip_prefix("existing target is stowed to a different package: ")
+        {
+            if let Some((target, source)) = rest.split_once(" => ") {
+                return StowConflict::StowedToDifferentPackage {
+                    target: target.into(),
+                    source: source.into(),
+                };
+            }
+        }
+        if let Some(rest) = message.strip_prefix("cannot stow ") {
+            if let Some((source, rest)) = rest.split_once(" over existing target ") {
+                if let Some((target, reason)) = rest.split_once(" since ") {
+                    return StowConflict::CannotStowOverExisting {
+                        source: source.into(),
+                        target: target.into(),
+                        reason: reason.to_string(),
+                    };
+                }
+            }
+        }
+        StowConflict::Unrecognized(message.to_string())
+    }
+
+    /// A suggestion for how the user can resolve this conflict.
+    pub fn hint(&self) -> String {
+        match self {
+            StowConflict::NeitherLinkNorDirectory { target }
+            | StowConflict::CannotStowOverExisting { target, .. } => format!(
+                "'{}' is a real file in the way; move it aside, or run stow with --adopt to pull it into the package",
+                target.display()
+            ),
+            StowConflict::NotOwnedByStow { target } => format!(
+                "'{}' is a symlink that stow did not create; remove it if it is no longer needed",
+                target.display()
+            ),
+            StowConflict::StowedToDifferentPackage { target, source } => format!(
+                "'{}' already links to '{}'; unstow that package with 'stow -D' or save into it instead",
+                target.display(),
+                source.display()
+            ),
+            StowConflict::Unrecognized(_) => "inspect the path mentioned by stow".to_string(),
+        }
+    }
+}
+
+impl fmt::Display for StowConflict {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            StowConflict::NeitherLinkNorDirectory { target } => write!(
+                f,
+                "existing target '{}' is neither a link nor a directory",
+                target.display()
+            ),
+            StowConflict::NotOwnedByStow { target } => {
+                write!(
+                    f,
+                    "existing target '{}' is not owned by stow",
+                    target.display()
+                )
+            }
+            StowConflict::StowedToDifferentPackage { target, source } => write!(
+                f,
+                "existing target '{}' is stowed to a different package ('{}')",
+                target.display(),
+                source.display()
+            ),
+            StowConflict::CannotStowOverExisting {
+                source,
+                target,
+                reason,
+            } => write!(
+                f,
+                "cannot stow '{}' over existing target '{}' since {}",
+                source.display(),
+                target.display(),
+                reason
+            ),
+            StowConflict::Unrecognized(message) => write!(f, "{}", message),
+        }
+    }
+}
+
+/// Command line options passed to stow along with the package name.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct StowOptions {
+    /// The stow directory, given with `--dir`.
+    pub dir: Option<PathBuf>,
+    /// The directory the package is linked into, given with `--target`.
+    pub target: Option<PathBuf>,
+    /// Regexes for files that stow leaves alone, matched against the end of their path.
+    pub ignore: Vec<String>,
+    pub defer: Vec<String>,
+    pub overrides: Vec<String>,
+    /// Move files that are in the way into the package instead of reporting a conflict.
+    pub adopt: bool,
+    /// Link files individually instead of linking whole directories, so that files which were
+    /// left out of the package can keep living next to the links.
+    pub no_folding: bool,
+    /// Link package entries named `dot-foo` as `.foo`.
+    pub dotfiles: bool,
+    pub verbose: u8,
+}
+
+impl StowOptions {
+    /// Parse the options given with `--stow-arg`. Each argument has to carry its value, as in
+    /// `--ignore=\.swp`.
+    pub fn parse_args(fs: &dyn Filesystem, args: &[String]) -> Result<StowOptions> {
+        let mut options = StowOptions::default();
+        for arg in args {
+            if matches!(arg.split('=').next(), Some("-d" | "--dir")) {
+                return Err(anyhow!(
+                    "The stow directory is the parent of the package; '{}' can't change it",
+                    arg
+                ));
+            }
+            if !options.apply_arg(fs, arg, &mut || None) {
+                return Err(anyhow!("Unsupported stow argument '{}'", arg));
+            }
+        }
+        Ok(options)
+    }
+
+    /// Parse a `.stowrc` file. Arguments that we don't need to know about are skipped; stow reads
+    /// the file itself and deals with them.
+    fn parse_rc(fs: &dyn Filesystem, contents: &str) -> StowOptions {
+        let mut options = StowOptions::default();
+        let mut words = contents
+            .lines()
+            .filter(|line| !line.trim_start().starts_with('#'))
+            .flat_map(str::split_whitespace)
+            .map(str::to_string);
+        while let Some(word) = words.next() {
+            options.apply_arg(fs, &word, &mut || words.next());
+        }
+        options
+    }
+
+    /// Apply one command line argument. `next` supplies the value of options written as two
+    /// arguments, like `-t ~`. Returns whether the argument was understood.
+    fn apply_arg(
+        &mut self,
+        fs: &dyn Filesystem,
+        arg: &str,
+        next: &mut dyn FnMut() -> Option<String>,
+    ) -> bool {
+        let (name, inline_value) = match arg.split_once('=') {
+            Some((name, value)) if name.starts_with("--") => (name, Some(value.to_string())),
+            _ => (arg, None),
+        };
+        let mut value = || inline_value.clone().or_else(&mut *next);
+        match name {
+            "-d" | "--dir" => match value() {
+                Some(dir) => self.dir = Some(expand_path(fs, &dir)),
+                None => return false,
+            },
+            "-t" | "--target" => match value() {
+                Some(target) => self.target = Some(expand_path(fs, &target)),
+                None => return false,
+            },
+            "--ignore" | "--defer" | "--override" => {
+                let Some(regex) = value() else {
+                    return false;
+                };
+                match name {
+                    "--ignore" => self.ignore.push(regex),
+                    "--defer" => self.defer.push(regex),
+                    _ => self.overrides.push(regex),
+                }
+            }
+            "--adopt" => self.adopt = true,
+            "--no-folding" => self.no_folding = true,
+            "--dotfiles" => self.dotfiles = true,
+            "-v" | "--verbose" => match &inline_value {
+                Some(level) => match level.parse() {
+                    Ok(level) => self.verbose = level,
+                    Err(_) => return false,
+                },
+                None => self.verbose += 1,
+            },
+            _ => return false,
+        }
+        true
+    }
+
+    /// The path inside a package that stow links to `relative`, a path inside the target.
+    pub fn package_path(&self, relative: &Path) -> PathBuf {
+        if !self.dotfiles {
+            return relative.to_owned();
+        }
+        relative
+            .iter()
+            .map(|component| match component.as_bytes().strip_prefix(b".") {
+                Some(rest) if !rest.is_empty() && rest != b"." => {
+                    let mut renamed = OsString::from("dot-");
+                    renamed.push(OsStr::from_bytes(rest));
+                    renamed
+                }
+                _ => component.to_owned(),
+            })
+            .collect()
+    }
+
+    /// The `--target`, with a relative path taken relative to `pwd`, the directory stow runs in.
+    pub fn target_from(&self, pwd: &Path) -> Option<PathBuf> {
+        self.target.as_ref().map(|target| pwd.join(target))
+    }
+
+    /// Add `other` on top of these options. Lists are combined, and the directories of `other`
+    /// win.
+    pub fn merge(&mut self, other: StowOptions) {
+        if other.dir.is_some() {
+            self.dir = other.dir;
+        }
+        if other.target.is_some() {
+            self.target = other.target;
+        }
+        self.ignore.extend(other.ignore);
+        self.defer.extend(other.defer);
+        self.overrides.extend(other.overrides);
+        self.adopt |= other.adopt;
+        self.no_folding |= other.no_folding;
+        self.dotfiles |= other.dotfiles;
+        self.verbose = self.verbose.max(other.verbose);
+    }
+
+    pub fn args(&self) -> Vec<OsString> {
+        let mut args = Vec::new();
+        let with_value = |name: &str, value: &OsStr| {
+            let mut arg = OsString::from(name);
+            arg.push("=");
+            arg.push(value);
+            arg
+        };
+        if let Some(dir) = &self.dir {
+            args.push(with_value("--dir", dir.as_os_str()));
+        }
+        if let Some(target) = &self.target {
+            args.push(with_value("--target", target.as_os_str()));
+        }
+        for (name, regexes) in [
+            ("--ignore", &self.ignore),
+            ("--defer", &self.defer),
+            ("--override", &self.overrides),
+        ] {
+            for regex in regexes {
+                args.push(with_value(name, regex.as_ref()));
+            }
+        }
+        if self.adopt {
+            args.push("--adopt".into());
+        }
+        if self.no_folding {
+            args.push("--no-folding".into());
+        }
+        if self.dotfiles {
+            args.push("--dotfiles".into());
+        }
+        if self.verbose > 0 {
+            args.push(format!("--verbose={}", self.verbose).into());
+        }
+        args
+    }
+}
+
+/// Read the options from the `.stowrc` file at `path`, if there is one.
+pub fn read_stowrc(fs: &dyn Filesystem, path: &Path) -> Result<StowOptions> {
+    if !fs.is_file(path) {
+        return Ok(StowOptions::default());
+    }
+    let contents = fs
+        .read_to_string(path)
+        .with_context(|| format!("Failed to read '{}'", path.display()))?;
+    Ok(StowOptions::parse_rc(fs, &contents))
+}
+
+/// Read the options from `~/.stowrc`, if there is one.
+pub fn read_home_stowrc(fs: &dyn Filesystem) -> Result<StowOptions> {
+    match util::home_dir(fs) {
+        Some(home) => read_stowrc(fs, &home.join(".stowrc")),
+        None => Ok(StowOptions::default()),
+    }
+}
+
+/// The stow directory that stow uses when it isn't told on the command line: the `--dir` of a
+/// `.stowrc`, or else `$STOW_DIR`.
+pub fn default_stow_dir(fs: &dyn Filesystem, rc: &StowOptions) -> Option<PathBuf> {
+    rc.dir.clone().or_else(|| {
+        fs.var("STOW_DIR")
+            .filter(|dir| !dir.is_empty())
+            .map(|dir| expand_path(fs, &dir.to_string_lossy()))
+    })
+}
+
+/// Expand `~` and environment variables the way stow does for paths in `.stowrc`.
+fn expand_path(fs: &dyn Filesystem, path: &str) -> PathBuf {
+    static VARIABLE: LazyLock<Regex> = LazyLock::new(|| Regex::new(r"\$\{?(\w+)\}?").unwrap());
+    let expanded = VARIABLE.replace_all(path, |captures: &regex::Captures| {
+        fs.var(&captures[1])
+            .map(|value| value.to_string_lossy().into_owned())
+            .unwrap_or_default()
+    });
+    util::expand_tilde(&expanded, util::home_dir(fs).as_deref())
+}
+
+/// The ignore list stow uses for packages without a `.stow-local-ignore` file when there is no
+/// `~/.stow-global-ignore` either.
+const DEFAULT_IGNORE: &str = r"
+RCS
+.+,v
+CVS
+\.\#.+       # CVS conflict files / emacs lock files
+\.cvsignore
+\.svn
+_darcs
+\.hg
+\.git
+\.gitignore
+\.gitmodules
+.+~          # emacs backup files
+\#.*\#       # emacs autosave files
+^/README.*
+^/LICENSE.*
+^/COPYING
+";
+
+/// The rules stow uses to decide which entries of a package it doesn't link.
+#[derive(Debug)]
+pub struct IgnoreRules {
+    /// From `--ignore`, matched against the end of the path relative to the package.
+    suffixes: Vec<Regex>,
+    /// Patterns with a `/` from an ignore file, matched against the path relative to the package.
+    path: Option<Regex>,
+    /// Patterns without a `/` from an ignore file, matched against the file name.
+    segment: Option<Regex>,
+}
+
+impl IgnoreRules {
+    /// The rules for `package`: `options.ignore` plus the package's `.stow-local-ignore`, or
+    /// `~/.stow-global-ignore`, or stow's built-in list.
+    pub fn load(fs: &dyn Filesystem, package: &Path, options: &StowOptions) -> Result<IgnoreRules> {
+        let suffixes = options
+            .ignore
+            .iter()
+            .map(|regex| {
+                Regex::new(&format!("({})\\z", regex))
+                    .with_context(|| format!("Invalid ignore regex '{}'", regex))
+            })
+            .collect::<Result<_>>()?;
+        let local = package.join(".stow-local-ignore");
+        let global = util::home_dir(fs).map(|home| home.join(".stow-global-ignore"));
+        let ignore_file = [Some(local), global]
+            .into_iter()
+            .flatten()
+            .find(|path| fs.is_file(path));
+        let patterns = match &ignore_file {
+            Some(path) => fs
+                .read_to_string(path)
+                .with_context(|| format!("Failed to read '{}'", path.display()))?,
+            None => DEFAULT_IGNORE.to_string(),
+        };
+        let (path, segment) = parse_ignore_file(&patterns).with_context(|| match &ignore_file {
+            Some(path) => format!("Invalid regex in '{}'", path.display()),
+            None => "Invalid regex in the default ignore list".to_string(),
+        })?;
+        Ok(IgnoreRules {
+            suffixes,
+            path,
+            segment,
+        })
+    }
+
+    /// Whether stow skips `relative`, a path inside the package, or one of its parents.
+    pub fn ignores(&self, relative: &Path) -> bool {
+        let mut prefix = PathBuf::new();
+        relative.components().any(|component| {
+            prefix.push(component);
+            self.ignores_entry(&prefix.to_string_lossy())
+        })
+    }
+
+    fn ignores_entry(&self, target: &str) -> bool {
+        if self.suffixes.iter().any(|suffix| suffix.is_match(target)) {
+            return true;
+        }
+        if let Some(path) = &self.path {
+            if path.is_match(&format!("/{}", target)) {
+                return true;
+            }
+        }
+        let basename = target.rsplit('/').next().unwrap_or(target);
+        self.segment
+            .as_ref()
+            .is_some_and(|segment| segment.is_match(basename))
+    }
+}
+
+/// Split the lines of an ignore file into a regex for patterns containing a `/` and one for the
+/// other patterns.
+fn parse_ignore_file(contents: &str) -> Result<(Option<Regex>, Option<Regex>)> {
+    let comment = Regex::new(r"^#.*|\s+#.*").unwrap();
+    let mut paths = Vec::new();
+    let mut segments = Vec::new();
+    for line in contents.lines() {
+        let line = comment.replace(line, "").replace(r"\#", "#");
+        let line = line.trim();
+        if line.is_empty() {
+            continue;
+        }
+        if line.contains('/') {
+            paths.push(line.to_string());
+        } else {
+            segments.push(line.to_string());
+        }
+    }
+    let combine = |patterns: Vec<String>| -> Result<Option<Regex>> {
+        if patterns.is_empty() {
+            return Ok(None);
+        }
+        Ok(Some(Regex::new(&format!(
+            "(^|/)({})(/|$)",
+            patterns.join("|")
+        ))?))
+    };
+    Ok((combine(paths)?, combine(segments)?))
+}
+
+/// A version of GNU stow.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct StowVersion(pub u32, pub u32, pub u32);
+
+impl StowVersion {
+    /// The oldest stow that reads `.stowrc` and ignore lists and knows `--adopt`, all of which
+    /// stowsave relies on.
+    pub const MINIMUM: StowVersion = StowVersion(2, 1, 0);
+    /// The first stow with `--dotfiles`.
+    pub const DOTFILES: StowVersion = StowVersion(2, 3, 0);
+
+    /// Parse the output of `stow --version`, such as `stow (GNU Stow) version 2.3.1`.
+    fn parse(output: &str) -> Option<StowVersion> {
+        let version = output.split_whitespace().last()?;
+        let mut numbers = version.split('.').map(|number| number.parse::<u32>());
+        let major = numbers.next()?.ok()?;
+        let minor = numbers.next().unwrap_or(Ok(0)).ok()?;
+        let patch = numbers.next().unwrap_or(Ok(0)).ok()?;
+        Some(StowVersion(major, minor, patch))
+    }
+
+    pub fn supports_dotfiles(self) -> bool {
+        self >= StowVersion::DOTFILES
+    }
+}
+
+impl fmt::Display for StowVersion {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}.{}.{}", self.0, self.1, self.2)
+    }
+}
+
+/// Run `<stow> --version` and check that the installed stow is recent enough.
+pub fn detect_version(fs: &dyn Filesystem, stow: &Path) -> Result<StowVersion, StowError> {
+    let output =
+        fs.run(stow, &["--version".into()], None)
+            .map_err(|err| StowError::NotRunnable {
+                stow: stow.to_owned(),
+                reason: err.to_string(),
+            })?;
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    let version = StowVersion::parse(stdout.lines().next().unwrap_or_default())
+        .filter(|_| output.status.success())
+        .ok_or_else(|| StowError::UnknownVersion {
+            stow: stow.to_owned(),
+            output: stdout.trim_end().to_string(),
+        })?;
+    if version < StowVersion::MINIMUM {
+        return Err(StowError::UnsupportedVersion { version });
+    }
+    Ok(version)
+}
+
+/// Ways in which running stow can fail.
+#[derive(Error, Debug)]
+pub enum StowError {
+    #[error("Stow refused to stow package '{package}' due to conflicts:{}", format_conflicts(.conflicts))]
+    Conflicts {
+        package: String,
+        conflicts: Vec<StowConflict>,
+    },
+    #[error("Stow directory does not contain package '{package}'")]
+    MissingPackage { package: String },
+    #[error("Failed to run 'stow {package}': {stderr}")]
+    Failed { package: String, stderr: String },
+    #[error("Failed to run '{}': {reason}. Use --stow-bin or STOWSAVE_STOW to point at stow", .stow.display())]
+    NotRunnable { stow: PathBuf, reason: String },
+    #[error("Could not tell the version of '{}' from its --version output: {output}", .stow.display())]
+    UnknownVersion { stow: PathBuf, output: String },
+    #[error(
+        "Stow {version} is too old; stowsave needs at least {}",
+        StowVersion::MINIMUM
+    )]
+    UnsupportedVersion { version: StowVersion },
+}
+
+impl StowError {
+    /// A stable identifier of the kind of error, for scripts that read `--output json`.
+    pub fn code(&self) -> &'static str {
+        match self {
+            StowError::Conflicts { .. } => "stow_conflicts",
+            StowError::MissingPackage { .. } => "stow_missing_package",
+            StowError::Failed { .. } => "stow_failed",
+            StowError::NotRunnable { .. } => "stow_not_runnable",
+            StowError::UnknownVersion { .. } => "stow_unknown_version",
+            StowError::UnsupportedVersion { .. } => "stow_unsupported_version",
+        }
+    }
+}
+
+fn format_conflicts(conflicts: &[StowConflict]) -> String {
+    conflicts
+        .iter()
+        .map(|conflict| format!("\n  * {}\n    hint: {}", conflict, conflict.hint()))
+        .collect()
+}
+
+/// Turn the stderr of a failed stow invocation into a [`StowError`].
+pub(super) fn parse_stderr(package: &str, stderr: &str) -> StowError {
+    let conflicts: Vec<StowConflict> = stderr
+        .lines()
+        .filter_map(|line| line.trim_start().strip_prefix("* "))
+        .map(|message| StowConflict::parse(message.trim_end()))
+        .collect();
+    if !conflicts.is_empty() {
+        return StowError::Conflicts {
+            package: package.to_string(),
+            conflicts,
+        };
+    }
+    if stderr.contains("does not contain package") {
+        return StowError::MissingPackage {
+            package: package.to_string(),
+        };
+    }
+    StowError::Failed {
+        package: package.to_string(),
+        stderr: stderr.trim_end().to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::filesystem::{MemoryFs, RealFs};
+
+    #[test]
+    fn test_stow_options_args() {
+        assert!(StowOptions::default().args().is_empty());
+        let options = StowOptions {
+            dir: Some("/home/user/dotfiles".into()),
+            ignore: vec![r"\.swp".to_string()],
+            adopt: true,
+            no_folding: true,
+            verbose: 2,
+            ..StowOptions::default()
+        };
+        assert_eq!(
+            options.args(),
+            vec![
+                "--dir=/home/user/dotfiles",
+                r"--ignore=\.swp",
+                "--adopt",
+                "--no-folding",
+                "--verbose=2"
+            ]
+        );
+    }
+
+    #[test]
+    fn test_parse_version() {
+        assert_eq!(
+            StowVersion::parse("stow (GNU Stow) version 2.3.1"),
+            Some(StowVersion(2, 3, 1))
+        );
+        assert_eq!(
+            StowVersion::parse("stow (GNU Stow) version 2.4"),
+            Some(StowVersion(2, 4, 0))
+        );
+        assert_eq!(StowVersion::parse("stow: unknown option"), None);
+        assert!(StowVersion(2, 3, 1).supports_dotfiles());
+        assert!(!StowVersion(2, 2, 2).supports_dotfiles());
+        assert!(StowVersion(2, 0, 1) < StowVersion::MINIMUM);
+    }
+
+    #[test]
+    fn test_detect_version_of_missing_stow() {
+        assert!(matches!(
+            detect_version(&RealFs, Path::new("/nonexistent/stow")),
+            Err(StowError::NotRunnable { .. })
+        ));
+    }
+
+    #[test]
+    fn test_package_path_with_dotfiles() {
+        let relative = Path::new(".config/nvim/init.lua");
+        assert_eq!(StowOptions::default().package_path(relative), relative);
+        let options = StowOptions {
+            dotfiles: true,
+            ..StowOptions::default()
+        };
+        assert_eq!(
+            options.package_path(relative),
+            Path::new("dot-config/nvim/init.lua")
+        );
+    }
+
+    #[test]
+    fn test_parse_stow_args() {
+        let args: Vec<String> = ["--ignore=\\.swp", "--defer=man", "-v", "--override=bin"]
+            .iter()
+            .map(|arg| arg.to_string())
+            .collect();
+        let fs = MemoryFs::new();
+        let options = StowOptions::parse_args(&fs, &args).unwrap();
+        assert_eq!(options.ignore, vec![r"\.swp"]);
+        assert_eq!(options.defer, vec!["man"]);
+        assert_eq!(options.overrides, vec!["bin"]);
+        assert_eq!(options.verbose, 1);
+
+        assert!(StowOptions::parse_args(&fs, &["--delete".to_string()]).is_err());
+        assert!(StowOptions::parse_args(&fs, &["--dir=/tmp".to_string()]).is_err());
+        // Values have to be given inline.
+        assert!(StowOptions::parse_args(&fs, &["--ignore".to_string()]).is_err());
+    }
+
+    #[test]
+    fn test_parse_stowrc() {
+        let fs = MemoryFs::new();
+        fs.set_var("HOME", "/home/user");
+        fs.set_var("DOTFILES", "/srv/dotfiles");
+        let options = StowOptions::parse_rc(
+            &fs,
+            "# Comment\n--dir=${DOTFILES}\n-t ~\n--ignore=\\.orig --restow\n",
+        );
+        assert_eq!(options.dir, Some(PathBuf::from("/srv/dotfiles")));
+        assert_eq!(options.target, Some(PathBuf::from("/home/user")));
+        assert_eq!(options.ignore, vec![r"\.orig"]);
+    }
+
+    #[test]
+    fn test_merge_stow_options() {
+        let mut options = StowOptions {
+            target: Some("/home/user".into()),
+            ignore: vec!["a".to_string()],
+            ..StowOptions::default()
+        };
+        options.merge(StowOptions {
+            target: Some("/srv".into()),
+            ignore: vec!["b".to_string()],
+            adopt: true,
+            ..StowOptions::default()
+        });
+        assert_eq!(options.target, Some(PathBuf::from("/srv")));
+        assert_eq!(options.ignore, vec!["a", "b"]);
+        assert!(options.adopt);
+    }
+
+    #[test]
+    fn test_relative_target_is_relative_to_pwd() {
+        let fs = MemoryFs::new();
+        let options = StowOptions::parse_rc(&fs, "--target=..\n");
+        assert_eq!(
+            options.target_from(Path::new("/home/user/dotfiles")),
+            Some(PathBuf::from("/home/user/dotfiles/.."))
+        );
+        let options = StowOptions::parse_rc(&fs, "--target=/srv\n");
+        assert_eq!(
+            options.target_from(Path::new("/home/user/dotfiles")),
+            Some(PathBuf::from("/srv"))
+        );
+    }
+
+    #[test]
+    fn test_default_ignore_rules() {
+        let fs = MemoryFs::new();
+        fs.create_dir(Path::new("/vim")).unwrap();
+        let options = StowOptions {
+            ignore: vec![r"\.swp".to_string()],
+            ..StowOptions::default()
+        };
+        let rules = IgnoreRules::load(&fs, Path::new("/vim"), &options).unwrap();
+        assert!(rules.ignores(Path::new(".gitignore")));
+        assert!(rules.ignores(Path::new(".config/nvim/.git/config")));
+        assert!(rules.ignores(Path::new("README.md")));
+        assert!(rules.ignores(Path::new(".vimrc.swp")));
+        assert!(rules.ignores(Path::new(".emacs~")));
+        assert!(!rules.ignores(Path::new(".config/README.md")));
+        assert!(!rules.ignores(Path::new(".vimrc")));
+        assert!(!rules.ignores(Path::new(".github")));
+    }
+
+    #[test]
+    fn test_local_ignore_rules() {
+        let fs = MemoryFs::new();
+        fs.create_dir(Path::new("/vim")).unwrap();
+        fs.write(
+            Path::new("/vim/.stow-local-ignore"),
+            "# Comment\n\\.cache    # trailing comment\n^/docs\n",
+        )
+        .unwrap();
+        let rules = IgnoreRules::load(&fs, Path::new("/vim"), &StowOptions::default()).unwrap();
+        assert!(rules.ignores(Path::new(".config/app/.cache")));
+        assert!(rules.ignores(Path::new("docs/index.md")));
+        // The local list replaces the built-in one.
+        assert!(!rules.ignores(Path::new(".gitignore")));
+    }
+
+    #[test]
+    fn test_parse_conflicts() {
+        let stderr = "\
+WARNING! stowing vim would cause conflicts:
+  * existing target is neither a link nor a directory: .vimrc
+  * existing target is not owned by stow: .vim
+  * existing target is stowed to a different package: .gvimrc => dotfiles/gvim/.gvimrc
+  * cannot stow dotfiles/vim/.viminfo over existing target .viminfo since neither a link nor a directory and --adopt not specified
+All operations aborted.
+";
+        let StowError::Conflicts { package, conflicts } = parse_stderr("vim", stderr) else {
+            panic!("expected conflicts");
+        };
+        assert_eq!(package, "vim");
+        assert_eq!(
+            conflicts,
+            vec![
+                StowConflict::NeitherLinkNorDirectory {
+                    target: ".vimrc".into()
+                },
+                StowConflict::NotOwnedByStow {
+                    target: ".vim".into()
+                },
+                StowConflict::StowedToDifferentPackage {
+                    target: ".gvimrc".into(),
+                    source: "dotfiles/gvim/.gvimrc".into()
+                },
+                StowConflict::CannotStowOverExisting {
+                    source: "dotfiles/vim/.viminfo".into(),
+                    target: ".viminfo".into(),
+                    reason: "neither a link nor a directory and --adopt not specified".into()
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn test_parse_unrecognized_conflict() {
+        let stderr = "WARNING! stowing vim would cause conflicts:\n  * something new\n";
+        let StowError::Conflicts { conflicts, .. } = parse_stderr("vim", stderr) else {
+            panic!("expected conflicts");
+        };
+        assert_eq!(
+            conflicts,
+            vec![StowConflict::Unrecognized("something new".into())]
+        );
+    }
+
+    #[test]
+    fn test_parse_missing_package() {
+        let stderr =
+            "stow: ERROR: The stow directory /home/user/dotfiles does not contain package vim\n";
+        assert!(matches!(
+            parse_stderr("vim", stderr),
+            StowError::MissingPackage { .. }
+        ));
+    }
+
+    #[test]
+    fn test_parse_other_failure() {
+        let error = parse_stderr("vim", "Can't locate Stow.pm in @INC\n");
+        assert!(matches!(error, StowError::Failed { .. }));
+        assert_eq!(
+            error.to_string(),
+            "Failed to run 'stow vim': Can't locate Stow.pm in @INC"
+        );
+    }
+}
@@ -0,0 +1,527 @@
+//! Moving files and directories without risking data loss.
+//!
+//! A move within one filesystem is a single atomic `rename`. Across filesystems the data has to
+//! be copied, so we copy to a temporary name next to the destination, check that the copy is
+//! identical to the source, rename it into place and only then remove the source. The same
+//! copying path is used when part of the source tree has to stay behind, either because it is
+//! left out of the [`Selection`] or because it is a special file.
+use std::collections::BTreeMap;
+use std::ffi::OsString;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use anyhow::{anyhow, Context, Result};
+use sha2::{Digest, Sha256};
+
+use crate::copy::copy_tree;
+use crate::filesystem::Filesystem;
+use crate::selection::{Selection, Visit};
+
+/// Move the entries of `from` that `selection` takes to `to`, which must not exist yet.
+///
+/// Entries that aren't selected stay where they are, together with the directories that
+/// contain them.
+pub(super) fn move_path(
+    fs: &dyn Filesystem,
+    from: &Path,
+    to: &Path,
+    selection: &Selection,
+) -> Result<()> {
+    if fs.symlink_metadata(to).is_ok() {
+        return Err(anyhow!("Destination '{}' already exists", to.display()));
+    }
+    let dest_dir = to
+        .parent()
+        .ok_or_else(|| anyhow!("Destination '{}' has no parent", to.display()))?;
+    let from_dev = fs
+        .symlink_metadata(from)
+        .with_context(|| format!("Failed to read metadata of '{}'", from.display()))?
+        .dev;
+    let dest_dev = fs
+        .metadata(dest_dir)
+        .with_context(|| format!("Failed to read metadata of '{}'", dest_dir.display()))?
+        .dev;
+    let leaves_files_behind = !selection
+        .partition(fs, from)
+        .with_context(|| format!("Failed to scan '{}'", from.display()))?
+        .left_behind
+        .is_empty();
+    if from_dev == dest_dev && !leaves_files_behind {
+        fs.rename(from, to).with_context(|| {
+            format!(
+                "Failed to rename '{}' to '{}'",
+                from.display(),
+                to.display()
+            )
+        })
+    } else {
+        copy_then_remove(fs, from, to, selection)
+    }
+}
+
+/// Move `from` to `to` like [`move_path`], except that `to` may already exist. Directories that
+/// exist in both places are merged, which puts back what a move of part of a tree took away.
+pub(super) fn merge_path(fs: &dyn Filesystem, from: &Path, to: &Path) -> Result<()> {
+    let from_is_dir = fs
+        .symlink_metadata(from)
+        .with_context(|| format!("Failed to read metadata of '{}'", from.display()))?
+        .is_dir();
+    match fs.symlink_metadata(to) {
+        Err(_) => move_path(fs, from, to, &Selection::default()),
+        Ok(metadata) if metadata.is_dir() && from_is_dir => {
+            let entries = fs
+                .read_dir(from)
+                .with_context(|| format!("Failed to read '{}'", from.display()))?;
+            for entry in entries {
+                merge_path(fs, &entry, &to.join(entry.file_name().unwrap()))?;
+            }
+            fs.remove_dir(from)
+                .with_context(|| format!("Failed to remove '{}'", from.display()))
+        }
+        Ok(_) => Err(anyhow!("Destination '{}' already exists", to.display())),
+    }
+}
+
+/// Copy `from` to a temporary sibling of `to`, verify it, rename it to `to` and remove what was
+/// copied from `from`.
+fn copy_then_remove(
+    fs: &dyn Filesystem,
+    from: &Path,
+    to: &Path,
+    selection: &Selection,
+) -> Result<()> {
+    let temp = temp_path_for(to);
+    if fs.symlink_metadata(&temp).is_ok() {
+        return Err(anyhow!(
+            "Temporary path '{}' already exists; remove it if it was left over from an interrupted run",
+            temp.display()
+        ));
+    }
+    log::trace!(
+        "Copying '{}' to '{}' by way of '{}'",
+        from.display(),
+        to.display(),
+        temp.display()
+    );
+    let copied = copy_tree(fs, from, &temp, selection)
+        .with_context(|| {
+            format!(
+                "Failed to copy '{}' to '{}'",
+                from.display(),
+                temp.display()
+            )
+        })
+        .and_then(|()| verify_copy(fs, from, &temp, selection));
+    if let Err(err) = copied {
+        // The source is untouched, so throwing away the partial copy loses nothing.
+        let _ = remove_path(fs, &temp);
+        return Err(err);
+    }
+    fs.rename(&temp, to).with_context(|| {
+        format!(
+            "Failed to rename '{}' to '{}'",
+            temp.display(),
+            to.display()
+        )
+    })?;
+    remove_copied(fs, from, Path::new(""), Visit::Descend, selection)
+        .map(|_| ())
+        .with_context(|| {
+            format!(
+                "Copied '{}' to '{}' but failed to remove the original",
+                from.display(),
+                to.display()
+            )
+        })
+}
+
+fn temp_path_for(to: &Path) -> PathBuf {
+    let mut name = OsString::from(".");
+    name.push(to.file_name().unwrap_or_default());
+    name.push(".stowsave-tmp");
+    to.with_file_name(name)
+}
+
+/// Remove the entries at and below `path` that `selection` takes. Directories that still contain
+/// something are kept, and so are directories that held nothing selected in the first place.
+/// Returns whether `path` itself was removed.
+fn remove_copied(
+    fs: &dyn Filesystem,
+    path: &Path,
+    relative: &Path,
+    parent: Visit,
+    selection: &Selection,
+) -> io::Result<bool> {
+    let file_type = fs.symlink_metadata(path)?.file_type;
+    let visit = selection.visit(relative, file_type, parent);
+    if file_type.is_dir() && visit != Visit::Skip {
+        let mut emptied = true;
+        let mut removed_any = false;
+        for entry in fs.read_dir(path)? {
+            let child_relative = relative.join(entry.file_name().unwrap());
+            let removed = remove_copied(fs, &entry, &child_relative, visit, selection)?;
+            emptied &= removed;
+            removed_any |= removed;
+        }
+        // An empty directory is only copied if the directory itself is selected.
+        let copied = visit == Visit::Take || removed_any;
+        if emptied && copied {
+            fs.remove_dir(path)?;
+        }
+        Ok(emptied && copied)
+    } else if visit == Visit::Take {
+        fs.remove_file(path)?;
+        Ok(true)
+    } else {
+        Ok(false)
+    }
+}
+
+fn remove_path(fs: &dyn Filesystem, path: &Path) -> io::Result<()> {
+    if fs.symlink_metadata(path)?.is_dir() {
+        fs.remove_dir_all(path)
+    } else {
+        fs.remove_file(path)
+    }
+}
+
+/// What we compare between a source tree and its copy.
+#[derive(Debug, PartialEq, Eq)]
+enum Entry {
+    File([u8; 32]),
+    Dir,
+    Symlink(PathBuf),
+    Fifo,
+}
+
+/// Every entry of a tree, keyed by its path relative to the root.
+type TreeDigest = BTreeMap<PathBuf, Entry>;
+
+/// Digest the entries of the tree at `root` that `selection` takes.
+fn digest_tree(fs: &dyn Filesystem, root: &Path, selection: &Selection) -> io::Result<TreeDigest> {
+    let mut digest = TreeDigest::new();
+    digest_entry(
+        fs,
+        root,
+        PathBuf::new(),
+        Visit::Descend,
+        selection,
+        &mut digest,
+    )?;
+    Ok(digest)
+}
+
+/// Digest one entry and what's below it. Returns whether a copy would contain the entry.
+fn digest_entry(
+    fs: &dyn Filesystem,
+    path: &Path,
+    relative: PathBuf,
+    parent: Visit,
+    selection: &Selection,
+    digest: &mut TreeDigest,
+) -> io::Result<bool> {
+    let file_type = fs.symlink_metadata(path)?.file_type;
+    let visit = selection.visit(&relative, file_type, parent);
+    if visit == Visit::Skip {
+        return Ok(false);
+    }
+    let entry = if file_type.is_dir() {
+        let mut digested_any = false;
+        for child in fs.read_dir(path)? {
+            let child_relative = relative.join(child.file_name().unwrap());
+            digested_any |= digest_entry(fs, &child, child_relative, visit, selection, digest)?;
+        }
+        if visit == Visit::Descend && !digested_any {
+            return Ok(false);
+        }
+        Entry::Dir
+    } else if visit == Visit::Descend {
+        return Ok(false);
+    } else if file_type.is_symlink() {
+        Entry::Symlink(fs.read_link(path)?)
+    } else if file_type.is_fifo() {
+        Entry::Fifo
+    } else {
+        let mut hasher = Sha256::new();
+        io::copy(&mut fs.open(path)?, &mut hasher)?;
+        Entry::File(hasher.finalize().into())
+    };
+    digest.insert(relative, entry);
+    Ok(true)
+}
+
+/// Check that `copy` has the same entries, file contents and symlink targets as the part of
+/// `original` that `selection` takes.
+fn verify_copy(
+    fs: &dyn Filesystem,
+    original: &Path,
+    copy: &Path,
+    selection: &Selection,
+) -> Result<()> {
+    let expected = digest_tree(fs, original, selection)
+        .with_context(|| format!("Failed to read '{}' for verification", original.display()))?;
+    let actual = digest_tree(fs, copy, &Selection::default())
+        .with_context(|| format!("Failed to read '{}' for verification", copy.display()))?;
+    if expected.len() != actual.len() {
+        return Err(anyhow!(
+            "Copy of '{}' has {} entries but the original has {}",
+            original.display(),
+            actual.len(),
+            expected.len()
+        ));
+    }
+    for (relative, entry) in &expected {
+        if actual.get(relative) != Some(entry) {
+            return Err(anyhow!(
+                "Copy of '{}' differs from the original",
+                original.join(relative).display()
+            ));
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use std::os::unix::fs::{symlink, FileTypeExt};
+
+    use tempfile::TempDir;
+
+    use super::*;
+    use crate::copy::SpecialFiles;
+    use crate::filesystem::{MemoryFs, RealFs};
+
+    fn create_tree(root: &Path) {
+        fs::create_dir_all(root.join("sub")).unwrap();
+        fs::write(root.join("file.txt"), "top").unwrap();
+        fs::write(root.join("sub").join("nested.txt"), "nested").unwrap();
+        symlink("file.txt", root.join("link")).unwrap();
+    }
+
+    #[test]
+    fn test_move_path_same_device() {
+        let temp_dir = TempDir::new().unwrap();
+        let from = temp_dir.path().join("tree");
+        create_tree(&from);
+        let to = temp_dir.path().join("moved");
+
+        move_path(&RealFs, &from, &to, &Selection::default()).unwrap();
+
+        assert!(!from.exists());
+        assert_eq!(
+            fs::read_to_string(to.join("sub/nested.txt")).unwrap(),
+            "nested"
+        );
+        assert_eq!(
+            fs::read_link(to.join("link")).unwrap(),
+            Path::new("file.txt")
+        );
+    }
+
+    #[test]
+    fn test_copy_then_remove_non_utf8_names() {
+        use std::os::unix::ffi::OsStrExt;
+
+        let temp_dir = TempDir::new().unwrap();
+        let name = std::ffi::OsStr::from_bytes(b"na\xefve");
+        let from = temp_dir.path().join(name);
+        create_tree(&from);
+        fs::write(from.join(name), "latin-1").unwrap();
+        let to = temp_dir.path().join("moved");
+
+        copy_then_remove(&RealFs, &from, &to, &Selection::default()).unwrap();
+
+        assert!(!from.exists());
+        assert_eq!(fs::read_to_string(to.join(name)).unwrap(), "latin-1");
+    }
+
+    #[test]
+    fn test_move_path_refuses_existing_destination() {
+        let temp_dir = TempDir::new().unwrap();
+        let from = temp_dir.path().join("from.txt");
+        let to = temp_dir.path().join("to.txt");
+        fs::write(&from, "new").unwrap();
+        fs::write(&to, "old").unwrap();
+
+        assert!(move_path(&RealFs, &from, &to, &Selection::default()).is_err());
+        assert_eq!(fs::read_to_string(&from).unwrap(), "new");
+        assert_eq!(fs::read_to_string(&to).unwrap(), "old");
+    }
+
+    #[test]
+    fn test_copy_then_remove() {
+        let temp_dir = TempDir::new().unwrap();
+        let from = temp_dir.path().join("tree");
+        create_tree(&from);
+        let to = temp_dir.path().join("moved");
+
+        copy_then_remove(&RealFs, &from, &to, &Selection::default()).unwrap();
+
+        assert!(!from.exists());
+        assert!(!temp_path_for(&to).exists());
+        assert_eq!(fs::read_to_string(to.join("file.txt")).unwrap(), "top");
+        assert_eq!(
+            fs::read_to_string(to.join("sub/nested.txt")).unwrap(),
+            "nested"
+        );
+        assert!(to.join("link").is_symlink());
+    }
+
+    #[test]
+    fn test_copy_then_remove_keeps_source_when_copy_fails() {
+        let temp_dir = TempDir::new().unwrap();
+        let from = temp_dir.path().join("from.txt");
+        fs::write(&from, "content").unwrap();
+        // The temporary copy can't be created inside a directory that doesn't exist.
+        let to = temp_dir.path().join("missing").join("from.txt");
+
+        assert!(copy_then_remove(&RealFs, &from, &to, &Selection::default()).is_err());
+        assert_eq!(fs::read_to_string(&from).unwrap(), "content");
+    }
+
+    #[test]
+    fn test_move_to_other_filesystem() {
+        // /dev/shm is usually a tmpfs, so it lives on a different device than the temp dir.
+        let shm = Path::new("/dev/shm");
+        if !shm.is_dir() {
+            println!("/dev/shm is not available on this system. Skipping test.");
+            return;
+        }
+        let source_dir = TempDir::new().unwrap();
+        let dest_dir = TempDir::new_in(shm).unwrap();
+        let from = source_dir.path().join("tree");
+        create_tree(&from);
+        let to = dest_dir.path().join("tree");
+
+        move_path(&RealFs, &from, &to, &Selection::default()).unwrap();
+
+        assert!(!from.exists());
+        assert_eq!(
+            fs::read_to_string(to.join("sub/nested.txt")).unwrap(),
+            "nested"
+        );
+    }
+
+    #[test]
+    fn test_verify_copy_detects_differences() {
+        let temp_dir = TempDir::new().unwrap();
+        let original = temp_dir.path().join("original");
+        let copy = temp_dir.path().join("copy");
+        create_tree(&original);
+        copy_tree(&RealFs, &original, &copy, &Selection::default()).unwrap();
+        verify_copy(&RealFs, &original, &copy, &Selection::default()).unwrap();
+
+        // Different contents
+        fs::write(copy.join("sub/nested.txt"), "truncat").unwrap();
+        assert!(verify_copy(&RealFs, &original, &copy, &Selection::default()).is_err());
+
+        // Missing file
+        fs::remove_file(copy.join("sub/nested.txt")).unwrap();
+        assert!(verify_copy(&RealFs, &original, &copy, &Selection::default()).is_err());
+    }
+
+    #[test]
+    fn test_move_path_leaves_excluded_special_files_behind() {
+        let temp_dir = TempDir::new().unwrap();
+        let from = temp_dir.path().join(".gnupg");
+        create_tree(&from);
+        let socket = from.join("sub").join("S.gpg-agent");
+        let _listener = std::os::unix::net::UnixListener::bind(&socket).unwrap();
+        let to = temp_dir.path().join("moved");
+
+        move_path(&RealFs, &from, &to, &SpecialFiles::Exclude.into()).unwrap();
+
+        // Only the socket and the directories leading to it remain.
+        assert!(socket.symlink_metadata().unwrap().file_type().is_socket());
+        assert_eq!(fs::read_dir(&from).unwrap().count(), 1);
+        assert_eq!(fs::read_dir(from.join("sub")).unwrap().count(), 1);
+        assert_eq!(fs::read_to_string(to.join("file.txt")).unwrap(), "top");
+        assert_eq!(
+            fs::read_to_string(to.join("sub/nested.txt")).unwrap(),
+            "nested"
+        );
+        assert!(to
+            .join("sub")
+            .join("S.gpg-agent")
+            .symlink_metadata()
+            .is_err());
+    }
+
+    #[test]
+    fn test_move_path_moves_selected_subset() {
+        let temp_dir = TempDir::new().unwrap();
+        let from = temp_dir.path().join("Code");
+        create_tree(&from);
+        fs::create_dir_all(from.join("Cache")).unwrap();
+        fs::write(from.join("Cache").join("data"), "cached").unwrap();
+        fs::create_dir(from.join("empty")).unwrap();
+        let to = temp_dir.path().join("moved");
+        let exclude = vec!["Cache".to_string()];
+        let include = vec!["sub/*.txt".to_string(), "file.txt".to_string()];
+        let selection = Selection::new(SpecialFiles::Abort, &exclude, &include).unwrap();
+
+        move_path(&RealFs, &from, &to, &selection).unwrap();
+
+        let mut left: Vec<_> = fs::read_dir(&from)
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name())
+            .collect();
+        left.sort();
+        assert_eq!(left, vec!["Cache", "empty", "link"]);
+        assert_eq!(fs::read_to_string(to.join("file.txt")).unwrap(), "top");
+        assert_eq!(
+            fs::read_to_string(to.join("sub/nested.txt")).unwrap(),
+            "nested"
+        );
+        // Directories that held nothing selected aren't recreated in the destination.
+        assert_eq!(fs::read_dir(&to).unwrap().count(), 2);
+    }
+
+    #[test]
+    fn test_move_path_across_devices_in_memory() {
+        let fs = MemoryFs::new();
+        let from = Path::new("/home/user/.config/app");
+        fs.create_dir_all(&from.join("sub")).unwrap();
+        fs.write(&from.join("sub/nested.txt"), "nested").unwrap();
+        fs.symlink(Path::new("sub/nested.txt"), &from.join("link"))
+            .unwrap();
+        fs.create_dir(Path::new("/mnt")).unwrap();
+        fs.mount(Path::new("/mnt"));
+        let to = Path::new("/mnt/app");
+
+        move_path(&fs, from, to, &Selection::default()).unwrap();
+
+        assert!(!fs.exists(from));
+        assert!(!fs.exists(&temp_path_for(to)));
+        assert_eq!(fs.read(&to.join("link")).unwrap(), b"nested");
+        assert_eq!(fs.metadata(to).unwrap().dev, 2);
+    }
+
+    #[test]
+    fn test_merge_path_restores_partial_move() {
+        let fs = MemoryFs::new();
+        let from = Path::new("/home/user/.config/Code");
+        fs.create_dir_all(&from.join("User")).unwrap();
+        fs.create_dir_all(&from.join("Cache")).unwrap();
+        fs.write(&from.join("User/settings.json"), "{}").unwrap();
+        fs.write(&from.join("Cache/data"), "cached").unwrap();
+        let to = Path::new("/home/user/dotfiles/Code");
+        fs.create_dir_all(to.parent().unwrap()).unwrap();
+        let exclude = vec!["Cache".to_string()];
+        let selection = Selection::new(SpecialFiles::Abort, &exclude, &[]).unwrap();
+
+        move_path(&fs, from, to, &selection).unwrap();
+        assert!(fs.is_file(&from.join("Cache/data")));
+        merge_path(&fs, to, from).unwrap();
+
+        assert!(!fs.exists(to));
+        assert_eq!(fs.read(&from.join("User/settings.json")).unwrap(), b"{}");
+        assert_eq!(fs.read(&from.join("Cache/data")).unwrap(), b"cached");
+
+        // Files are never replaced.
+        fs.write(Path::new("/a"), "a").unwrap();
+        fs.write(Path::new("/b"), "b").unwrap();
+        assert!(merge_path(&fs, Path::new("/a"), Path::new("/b")).is_err());
+    }
+}
@@ -0,0 +1,171 @@
+//! Measuring how much a save would put into the stow package, so that a directory full of caches
+//! doesn't end up in a git repository by accident.
+use std::cmp::Reverse;
+use std::collections::BTreeMap;
+use std::fmt;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use crate::filesystem::Filesystem;
+use crate::selection::{Selection, Visit};
+
+/// How many of the largest files and directories a [`Usage`] keeps.
+const LARGEST: usize = 5;
+
+/// The size of the entries of a tree that a [`Selection`] takes.
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct Usage {
+    /// The total size of the files, in bytes.
+    pub size: u64,
+    /// The number of files, symlinks and other entries that aren't directories.
+    pub files: usize,
+    /// The largest files with their sizes, largest first, relative to the root.
+    pub largest_files: Vec<(PathBuf, u64)>,
+    /// The largest directories directly inside the root with their total sizes, largest first.
+    pub largest_dirs: Vec<(PathBuf, u64)>,
+}
+
+impl Usage {
+    /// Walk the tree at `root` and add up what `selection` takes from it.
+    pub fn measure(fs: &dyn Filesystem, root: &Path, selection: &Selection) -> io::Result<Usage> {
+        let mut usage = Usage::default();
+        let mut dirs = BTreeMap::new();
+        measure_entry(
+            fs,
+            root,
+            Path::new(""),
+            Visit::Descend,
+            selection,
+            &mut usage,
+            &mut dirs,
+        )?;
+        usage.largest_files.sort_by_key(|(_, size)| Reverse(*size));
+        usage.largest_files.truncate(LARGEST);
+        usage.largest_dirs = dirs.into_iter().filter(|(_, size)| *size > 0).collect();
+        usage.largest_dirs.sort_by_key(|(_, size)| Reverse(*size));
+        usage.largest_dirs.truncate(LARGEST);
+        Ok(usage)
+    }
+}
+
+impl fmt::Display for Usage {
+    /// The totals followed by the largest files and directories, one per line.
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} in {} files", format_size(self.size), self.files)?;
+        for (title, entries) in [
+            ("Largest files", &self.largest_files),
+            ("Largest directories", &self.largest_dirs),
+        ] {
+            if !entries.is_empty() {
+                write!(f, "\n{}:", title)?;
+                for (path, size) in entries {
+                    write!(f, "\n  {:>9}  {}", format_size(*size), path.display())?;
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+fn measure_entry(
+    fs: &dyn Filesystem,
+    path: &Path,
+    relative: &Path,
+    parent: Visit,
+    selection: &Selection,
+    usage: &mut Usage,
+    dirs: &mut BTreeMap<PathBuf, u64>,
+) -> io::Result<()> {
+    let metadata = fs.symlink_metadata(path)?;
+    let visit = selection.visit(relative, metadata.file_type, parent);
+    if visit == Visit::Skip {
+        return Ok(());
+    }
+    if metadata.is_dir() {
+        for entry in fs.read_dir(path)? {
+            let child = relative.join(entry.file_name().unwrap());
+            measure_entry(fs, &entry, &child, visit, selection, usage, dirs)?;
+        }
+    } else if visit == Visit::Take {
+        usage.size += metadata.len;
+        usage.files += 1;
+        usage
+            .largest_files
+            .push((relative.to_owned(), metadata.len));
+        // Files below the root's subdirectories count towards the subdirectory.
+        let mut components = relative.components();
+        if let (Some(top), Some(_)) = (components.next(), components.next()) {
+            *dirs.entry(PathBuf::from(top.as_os_str())).or_default() += metadata.len;
+        }
+    }
+    Ok(())
+}
+
+/// A size in bytes, in the largest decimal unit that keeps it above 1, like `12.3 MB`.
+pub fn format_size(size: u64) -> String {
+    const UNITS: [&str; 4] = ["KB", "MB", "GB", "TB"];
+    if size < 1000 {
+        return format!("{} B", size);
+    }
+    let mut value = size as f64 / 1000.0;
+    let mut unit = 0;
+    while value >= 1000.0 && unit < UNITS.len() - 1 {
+        value /= 1000.0;
+        unit += 1;
+    }
+    format!("{:.1} {}", value, UNITS[unit])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::copy::SpecialFiles;
+    use crate::filesystem::MemoryFs;
+
+    #[test]
+    fn test_measure() {
+        let fs = MemoryFs::new();
+        let root = Path::new("/home/user/.config/Code");
+        fs.create_dir_all(&root.join("User/snippets")).unwrap();
+        fs.create_dir_all(&root.join("Cache")).unwrap();
+        fs.create_dir_all(&root.join("Empty")).unwrap();
+        fs.write(&root.join("User/settings.json"), "{}").unwrap();
+        fs.write(&root.join("User/snippets/rust.json"), "[1, 2]")
+            .unwrap();
+        fs.write(&root.join("Cache/data"), vec![0; 3000]).unwrap();
+        fs.write(&root.join("main.log"), vec![0; 100]).unwrap();
+
+        let usage = Usage::measure(&fs, root, &Selection::default()).unwrap();
+        assert_eq!(usage.size, 3108);
+        assert_eq!(usage.files, 4);
+        assert_eq!(
+            usage.largest_files[..2],
+            [
+                (PathBuf::from("Cache/data"), 3000),
+                (PathBuf::from("main.log"), 100)
+            ]
+        );
+        assert_eq!(
+            usage.largest_dirs,
+            vec![(PathBuf::from("Cache"), 3000), (PathBuf::from("User"), 8)]
+        );
+        assert!(usage.to_string().starts_with("3.1 KB in 4 files\n"));
+
+        let selection = Selection::new(SpecialFiles::Abort, &["Cache".into()], &[]).unwrap();
+        let usage = Usage::measure(&fs, root, &selection).unwrap();
+        assert_eq!((usage.size, usage.files), (108, 3));
+
+        let usage = Usage::measure(&fs, &root.join("Cache"), &Selection::default()).unwrap();
+        assert_eq!(
+            usage.to_string(),
+            "3.0 KB in 1 files\nLargest files:\n     3.0 KB  data"
+        );
+    }
+
+    #[test]
+    fn test_format_size() {
+        assert_eq!(format_size(999), "999 B");
+        assert_eq!(format_size(10_000_000), "10.0 MB");
+        assert_eq!(format_size(1_234_567_890), "1.2 GB");
+    }
+}
@@ -1,25 +1,46 @@
+use std::io;
 use std::path::{Path, PathBuf};
 
-/// Finds the common ancestor path between two absolute paths.
-pub(super) fn find_common_ancestor(path1: &Path, path2: &Path) -> PathBuf {
-    assert!(path1.is_absolute(), "Path1 must be absolute");
-    assert!(path2.is_absolute(), "Path2 must be absolute");
-    let mut ancestor = PathBuf::new();
-    let components1: Vec<_> = path1.components().collect();
-    let components2: Vec<_> = path2.components().collect();
+use crate::filesystem::Filesystem;
 
-    for (c1, c2) in components1.iter().zip(components2.iter()) {
-        if c1 == c2 {
-            ancestor.push(c1);
-        } else {
-            break;
-        }
+/// The user's home directory, from `$HOME`.
+pub(super) fn home_dir(fs: &dyn Filesystem) -> Option<PathBuf> {
+    fs.var("HOME")
+        .filter(|home| !home.is_empty())
+        .map(PathBuf::from)
+}
+
+/// Where stowsave keeps its state, like locks: `$XDG_STATE_HOME/stowsave`, or
+/// `~/.local/state/stowsave`.
+pub(super) fn state_dir(fs: &dyn Filesystem) -> Option<PathBuf> {
+    let state_home = match fs.var("XDG_STATE_HOME") {
+        Some(dir) if !dir.is_empty() => PathBuf::from(dir),
+        _ => home_dir(fs)?.join(".local").join("state"),
+    };
+    Some(state_home.join("stowsave"))
+}
+
+/// Replace a leading `~` with the user's home directory, `home`.
+pub(super) fn expand_tilde(path: &str, home: Option<&Path>) -> PathBuf {
+    match (path.strip_prefix('~'), home) {
+        (Some(""), Some(home)) => home.to_owned(),
+        (Some(rest), Some(home)) if rest.starts_with('/') => home.join(&rest[1..]),
+        _ => PathBuf::from(path),
     }
+}
 
-    if ancestor.as_os_str().is_empty() {
-        PathBuf::from("/")
-    } else {
-        ancestor
+/// Make `path` absolute and remove `.` and `..` components without touching the filesystem.
+pub(super) fn normalize_path(path: &Path) -> io::Result<PathBuf> {
+    Ok(path_clean::clean(std::path::absolute(path)?))
+}
+
+/// Make `path` absolute and resolve symlinks in its parent directories, but not in its final
+/// component, so that a symlink given on the command line isn't replaced by its target.
+pub(super) fn canonicalize_parent(fs: &dyn Filesystem, path: &Path) -> io::Result<PathBuf> {
+    let absolute = std::path::absolute(path)?;
+    match (absolute.parent(), absolute.file_name()) {
+        (Some(parent), Some(file_name)) => Ok(fs.canonicalize(parent)?.join(file_name)),
+        _ => fs.canonicalize(&absolute),
     }
 }
 
@@ -28,37 +49,47 @@ mod tests {
     use std::path::Path;
 
     use super::*;
+    use crate::filesystem::RealFs;
 
     #[test]
-    fn test_find_common_ancestor_same_path() {
-        let path = Path::new("/home/user/documents");
+    fn test_canonicalize_parent_keeps_final_symlink() {
+        let temp_dir = tempfile::TempDir::new().unwrap();
+        let real = temp_dir.path().canonicalize().unwrap();
+        std::fs::write(real.join("file"), "").unwrap();
+        std::os::unix::fs::symlink("file", real.join("link")).unwrap();
+        std::os::unix::fs::symlink(&real, real.join("dir_link")).unwrap();
+
         assert_eq!(
-            find_common_ancestor(path, path),
-            Path::new("/home/user/documents")
+            canonicalize_parent(&RealFs, &real.join("dir_link").join("link")).unwrap(),
+            real.join("link")
         );
     }
 
     #[test]
-    fn test_find_common_ancestor_subpath() {
-        let path1 = Path::new("/home/user/documents");
-        let path2 = Path::new("/home/user/documents/subfolder");
+    fn test_expand_tilde() {
+        let home = Some(Path::new("/home/user"));
+        assert_eq!(expand_tilde("~", home), Path::new("/home/user"));
         assert_eq!(
-            find_common_ancestor(path1, path2),
-            Path::new("/home/user/documents")
+            expand_tilde("~/.cache", home),
+            Path::new("/home/user/.cache")
         );
+        assert_eq!(
+            expand_tilde("~user/.cache", home),
+            Path::new("~user/.cache")
+        );
+        assert_eq!(expand_tilde("/proc", home), Path::new("/proc"));
+        assert_eq!(expand_tilde("~/.cache", None), Path::new("~/.cache"));
     }
 
     #[test]
-    fn test_find_common_ancestor_different_paths() {
-        let path1 = Path::new("/home/user1/documents");
-        let path2 = Path::new("/home/user2/pictures");
-        assert_eq!(find_common_ancestor(path1, path2), Path::new("/home"));
-    }
-
-    #[test]
-    fn test_find_common_ancestor_root() {
-        let path1 = Path::new("/home/user/documents");
-        let path2 = Path::new("/var/log");
-        assert_eq!(find_common_ancestor(path1, path2), Path::new("/"));
+    fn test_normalize_path() {
+        assert_eq!(
+            normalize_path(Path::new("/home/user/./dotfiles/../.vimrc")).unwrap(),
+            Path::new("/home/user/.vimrc")
+        );
+        assert_eq!(
+            normalize_path(Path::new("file")).unwrap(),
+            std::env::current_dir().unwrap().join("file")
+        );
     }
 }